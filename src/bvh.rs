@@ -0,0 +1,199 @@
+
+use crate::primitives::{Vec3, Aabb};
+use crate::scene::Hittable;
+
+// Number of centroid buckets tried along each axis when looking for a split
+const BUCKETS: usize = 12;
+// Leaves are allowed to hold up to this many primitives when splitting doesn't pay
+const MAX_LEAF: usize = 4;
+// Cost of stepping into a node, relative to one primitive intersection test
+const TRAVERSAL_COST: f32 = 0.125;
+
+struct BuildItem {
+    bbox: Aabb,
+    centroid: Vec3,
+    hittable: Hittable,
+}
+
+impl Hittable {
+    // Rebuild a HittableList as a bounding volume hierarchy, with boxes fitted
+    // to the shutter interval time0..time1. Anything else is returned unchanged.
+    pub fn into_bvh(self, time0: f32, time1: f32) -> Hittable {
+        match self {
+            Hittable::HittableList { hittables } => build_bvh(hittables, time0, time1),
+            other => other,
+        }
+    }
+}
+
+// Build a BVH over some objects using the surface area heuristic. Nested lists
+// are flattened so their members get sorted into the tree individually.
+// Objects with no bounding box (empty lists) can't be hit and are dropped.
+pub fn build_bvh(hittables: Vec<Hittable>, time0: f32, time1: f32) -> Hittable {
+    let mut items = Vec::with_capacity(hittables.len());
+    flatten_into(hittables, time0, time1, &mut items);
+    if items.is_empty() {
+        return Hittable::HittableList { hittables: Vec::new() };
+    }
+    build_node(items)
+}
+
+fn flatten_into(hittables: Vec<Hittable>, time0: f32, time1: f32, items: &mut Vec<BuildItem>) {
+    for hittable in hittables {
+        match hittable {
+            Hittable::HittableList { hittables } => flatten_into(hittables, time0, time1, items),
+            other => {
+                if let Some(bbox) = other.bounding_box(time0, time1) {
+                    items.push(BuildItem {
+                        bbox,
+                        centroid: bbox.centroid(),
+                        hittable: other,
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn make_leaf(items: Vec<BuildItem>) -> Hittable {
+    let mut hittables: Vec<Hittable> = items.into_iter().map(|item| item.hittable).collect();
+    if hittables.len() == 1 {
+        hittables.pop().unwrap()
+    } else {
+        Hittable::HittableList { hittables }
+    }
+}
+
+fn bucket_index(value: f32, low: f32, extent: f32) -> usize {
+    let b = ((value - low) / extent * BUCKETS as f32) as usize;
+    b.min(BUCKETS - 1)
+}
+
+fn build_node(items: Vec<BuildItem>) -> Hittable {
+    let count = items.len();
+    if count == 1 {
+        return make_leaf(items);
+    }
+
+    let bbox = items.iter().fold(Aabb::empty(), |acc, item| Aabb::surrounding(acc, item.bbox));
+    let centroid_bounds = items.iter().fold(Aabb::empty(), |acc, item| acc.grow(item.centroid));
+
+    // Costs are all left scaled by the parent's surface area. Skipping the
+    // division keeps degenerate (flat or point-like) nodes out of NaN land.
+    let leaf_cost = count as f32 * bbox.surface_area();
+    let mut best: Option<(f32, usize, usize)> = None; // (cost, axis, split bucket)
+
+    for axis in 0..3 {
+        let low = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - low;
+        if extent <= 0.0 {
+            continue; // every centroid is in the same spot along this axis
+        }
+
+        let mut counts = [0usize; BUCKETS];
+        let mut boxes = [Aabb::empty(); BUCKETS];
+        for item in &items {
+            let b = bucket_index(item.centroid[axis], low, extent);
+            counts[b] += 1;
+            boxes[b] = Aabb::surrounding(boxes[b], item.bbox);
+        }
+
+        for split in 1..BUCKETS {
+            let (left_count, left_box) = (0..split).fold((0, Aabb::empty()), |(n, acc), b| {
+                (n + counts[b], Aabb::surrounding(acc, boxes[b]))
+            });
+            let (right_count, right_box) = (split..BUCKETS).fold((0, Aabb::empty()), |(n, acc), b| {
+                (n + counts[b], Aabb::surrounding(acc, boxes[b]))
+            });
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = TRAVERSAL_COST * bbox.surface_area()
+                + left_count as f32 * left_box.surface_area()
+                + right_count as f32 * right_box.surface_area();
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, split));
+            }
+        }
+    }
+
+    let (left, right): (Vec<BuildItem>, Vec<BuildItem>) = match best {
+        Some((cost, axis, split)) if cost < leaf_cost || count > MAX_LEAF => {
+            let low = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - low;
+            items.into_iter()
+                .partition(|item| bucket_index(item.centroid[axis], low, extent) < split)
+        }
+        _ if count <= MAX_LEAF => return make_leaf(items),
+        _ => {
+            // Too many objects sharing one centroid to bucket apart. Split
+            // them down the middle so leaves stay small.
+            let mut items = items;
+            let right = items.split_off(count / 2);
+            (items, right)
+        }
+    };
+
+    Hittable::BvhNode {
+        bbox,
+        left: Box::new(build_node(left)),
+        right: Box::new(build_node(right)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Ray;
+    use crate::scene::Scene;
+    use crate::texture::Texture;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+    use rand::distributions::Uniform;
+
+    #[test]
+    fn bvh_matches_linear_list() {
+        // the bouncing version checks the boxes cover the whole shutter
+        for bouncing in [false, true] {
+            let mut rng = SmallRng::seed_from_u64(3);
+            let list = Scene::random_world(&mut rng, None, bouncing);
+            let bvh = list.clone().into_bvh(0.0, 1.0);
+            assert!(matches!(bvh, Hittable::BvhNode { .. }));
+
+            let distrib = Uniform::new(-1.0, 1.0);
+            for _ in 0..500 {
+                let ray = Ray {
+                    orig: Vec3::new(13.0, 2.0, 3.0) + Vec3::rand(&mut rng, distrib),
+                    dir: Vec3::new(-13.0, -2.0, -3.0) + Vec3::rand(&mut rng, distrib) * 4.0,
+                    time: rng.sample(Uniform::new(0.0, 1.0)),
+                };
+                let expected = list.hit(ray, 0.001, f32::INFINITY);
+                let actual = bvh.hit(ray, 0.001, f32::INFINITY);
+                assert_eq!(expected.map(|rec| (rec.t, rec.p)), actual.map(|rec| (rec.t, rec.p)));
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_bounds_everything() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let list = Scene::random_world(&mut rng, None, false);
+        let expected = list.bounding_box(0.0, 1.0);
+        assert_eq!(list.into_bvh(0.0, 1.0).bounding_box(0.0, 1.0), expected);
+    }
+
+    #[test]
+    fn bvh_handles_coincident_objects() {
+        let material = crate::scene::Material::Lambertian { albedo: Texture::solid(Vec3::ones()) };
+        let spheres = (0..50).map(|_| Hittable::Sphere {
+            center: Vec3::zero(),
+            radius: 1.0,
+            material: material.clone(),
+        }).collect();
+        let bvh = build_bvh(spheres, 0.0, 1.0);
+        let ray = Ray { orig: Vec3::new(0.0, 0.0, -5.0), dir: Vec3::new(0.0, 0.0, 1.0), time: 0.0 };
+        let rec = bvh.hit(ray, 0.001, f32::INFINITY).expect("ray should hit the spheres");
+        assert!((rec.t - 4.0).abs() < 1e-5);
+    }
+}
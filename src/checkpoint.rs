@@ -0,0 +1,312 @@
+
+// Saving a progressive render part way through, and picking it up again
+//
+// Every sample has its own random stream (see sampler::Sampler), so the
+// only "RNG state" worth saving is the seed and how many passes are done.
+// Along with the exact sums in the framebuffer, that's enough for a resumed
+// render to finish bit for bit the same as one that was never stopped.
+
+use crate::primitives::{Rect, Vec2i, Vec3};
+use crate::filter::{Filter, FilterKind};
+use crate::renderer::{Framebuffer, image_size_ok};
+use crate::sampler::SamplerKind;
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 16] = b"rustpt checkpnt\0";
+const VERSION: u32 = 5;
+
+// Everything that has to match for the saved samples to add up with new ones
+#[derive (Copy, Clone, PartialEq, Debug)]
+pub struct RenderIdentity {
+    pub scene_hash: u64, // fingerprint of the scene, see fnv1a()
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub filter: Filter,
+    pub bounces: u32,
+    pub roulette_depth: u32,
+    pub img_size: Vec2i,
+    pub region: Rect,
+}
+
+impl RenderIdentity {
+    // Describes the first difference, if there is one
+    pub fn mismatch(&self, other: &RenderIdentity) -> Option<String> {
+        if self.scene_hash != other.scene_hash {
+            Some("the scene has changed".to_string())
+        } else if self.seed != other.seed {
+            Some(format!("seed {} vs {}", self.seed, other.seed))
+        } else if self.sampler != other.sampler {
+            Some(format!("{:?} sampler vs {:?}", self.sampler, other.sampler))
+        } else if self.filter != other.filter {
+            Some(format!(
+                "{:?} filter ({} pixels) vs {:?} ({} pixels)",
+                self.filter.kind, self.filter.radius, other.filter.kind, other.filter.radius
+            ))
+        } else if self.bounces != other.bounces {
+            Some(format!("{} bounces vs {}", self.bounces, other.bounces))
+        } else if self.roulette_depth != other.roulette_depth {
+            Some(format!("roulette depth {} vs {}", self.roulette_depth, other.roulette_depth))
+        } else if self.img_size != other.img_size {
+            Some(format!(
+                "image size {}x{} vs {}x{}",
+                self.img_size.x, self.img_size.y, other.img_size.x, other.img_size.y
+            ))
+        } else if self.region != other.region {
+            Some("the region is different".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Checkpoint {
+    pub identity: RenderIdentity,
+    pub samples: u32,
+    pub framebuffer: Framebuffer,
+}
+
+#[derive (Debug)]
+pub enum CheckpointError {
+    Io { path: PathBuf, source: io::Error },
+    Format { path: PathBuf, message: String },
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            CheckpointError::Format { path, message } => {
+                write!(f, "{}: not a usable checkpoint ({})", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+// 64-bit FNV-1a. Unlike std's hashers it's guaranteed not to change between
+// builds, which matters for something written to disk.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+// How the sampler is stored. Numbers, so renaming a variant can't break old files.
+fn sampler_code(sampler: SamplerKind) -> u32 {
+    match sampler {
+        SamplerKind::Independent => 0,
+        SamplerKind::Stratified => 1,
+        SamplerKind::Halton => 2,
+        SamplerKind::Sobol => 3,
+    }
+}
+
+fn sampler_from_code(code: u32) -> Option<SamplerKind> {
+    match code {
+        0 => Some(SamplerKind::Independent),
+        1 => Some(SamplerKind::Stratified),
+        2 => Some(SamplerKind::Halton),
+        3 => Some(SamplerKind::Sobol),
+        _ => None,
+    }
+}
+
+fn filter_code(filter: FilterKind) -> u32 {
+    match filter {
+        FilterKind::Box => 0,
+        FilterKind::Tent => 1,
+        FilterKind::Gaussian => 2,
+        FilterKind::Mitchell => 3,
+    }
+}
+
+fn filter_from_code(code: u32) -> Option<FilterKind> {
+    match code {
+        0 => Some(FilterKind::Box),
+        1 => Some(FilterKind::Tent),
+        2 => Some(FilterKind::Gaussian),
+        3 => Some(FilterKind::Mitchell),
+        _ => None,
+    }
+}
+
+pub fn write_checkpoint(
+    out: &mut impl Write,
+    identity: &RenderIdentity,
+    framebuffer: &Framebuffer,
+    samples: u32,
+) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&identity.scene_hash.to_le_bytes())?;
+    out.write_all(&identity.seed.to_le_bytes())?;
+    out.write_all(&sampler_code(identity.sampler).to_le_bytes())?;
+    out.write_all(&filter_code(identity.filter.kind).to_le_bytes())?;
+    out.write_all(&identity.filter.radius.to_le_bytes())?;
+    out.write_all(&identity.bounces.to_le_bytes())?;
+    out.write_all(&identity.roulette_depth.to_le_bytes())?;
+    let r = identity.region;
+    for v in [identity.img_size.x, identity.img_size.y, r.x, r.y, r.w, r.h] {
+        out.write_all(&v.to_le_bytes())?;
+    }
+    out.write_all(&samples.to_le_bytes())?;
+    for (pixel, weight) in framebuffer.pixels.iter().zip(&framebuffer.weights) {
+        for v in [pixel.x, pixel.y, pixel.z, *weight] {
+            out.write_all(&v.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// Reads back what write_checkpoint wrote. Errors are plain strings, the
+// caller knows which file they came from.
+pub fn read_checkpoint(input: &mut impl Read) -> Result<Checkpoint, String> {
+    let mut header = [0u8; 16 + 4 + 8 + 8 + 4 + 4 + 4 + 4 + 4 + 6 * 4 + 4];
+    input.read_exact(&mut header).map_err(|_| "file is too short".to_string())?;
+    if &header[..16] != MAGIC {
+        return Err("wrong magic number".to_string());
+    }
+    let mut at = 16;
+    let mut take = |n: usize| {
+        let bytes = &header[at..at + n];
+        at += n;
+        bytes
+    };
+    let u32_at = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+    let i32_at = |b: &[u8]| i32::from_le_bytes(b.try_into().unwrap());
+    let u64_at = |b: &[u8]| u64::from_le_bytes(b.try_into().unwrap());
+
+    let version = u32_at(take(4));
+    if version != VERSION {
+        return Err(format!("version {}, expected {}", version, VERSION));
+    }
+    let scene_hash = u64_at(take(8));
+    let seed = u64_at(take(8));
+    let sampler = u32_at(take(4));
+    let filter_kind = u32_at(take(4));
+    let filter_radius = f32::from_le_bytes(take(4).try_into().unwrap());
+    let bounces = u32_at(take(4));
+    let roulette_depth = u32_at(take(4));
+    let img_size = Vec2i { x: i32_at(take(4)), y: i32_at(take(4)) };
+    let region = Rect { x: i32_at(take(4)), y: i32_at(take(4)), w: i32_at(take(4)), h: i32_at(take(4)) };
+    let samples = u32_at(take(4));
+
+    let sampler = sampler_from_code(sampler).ok_or_else(|| format!("unknown sampler {}", sampler))?;
+    let filter = Filter {
+        kind: filter_from_code(filter_kind).ok_or_else(|| format!("unknown filter {}", filter_kind))?,
+        radius: filter_radius,
+    };
+    if !image_size_ok(region.w, region.h) || !image_size_ok(img_size.x, img_size.y) {
+        return Err("bad image size".to_string());
+    }
+    let mut framebuffer = Framebuffer::for_region(region);
+    let mut data = vec![0u8; framebuffer.pixels.len() * 16];
+    input.read_exact(&mut data).map_err(|_| "pixel data is cut short".to_string())?;
+    if input.read(&mut [0u8]).map_err(|e| e.to_string())? != 0 {
+        return Err("unexpected data after the pixels".to_string());
+    }
+    for (i, bytes) in data.chunks_exact(16).enumerate() {
+        let f = |i: usize| f32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        framebuffer.pixels[i] = Vec3::new(f(0), f(4), f(8));
+        framebuffer.weights[i] = f(12);
+    }
+
+    Ok(Checkpoint {
+        identity: RenderIdentity { scene_hash, seed, sampler, filter, bounces, roulette_depth, img_size, region },
+        samples,
+        framebuffer,
+    })
+}
+
+// Write to a temporary file then swap it in, so a crash mid-write can't take
+// the previous checkpoint with it
+pub fn save_checkpoint(
+    path: impl AsRef<Path>,
+    identity: &RenderIdentity,
+    framebuffer: &Framebuffer,
+    samples: u32,
+) -> Result<(), CheckpointError> {
+    let path = path.as_ref();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let io_error = |source| CheckpointError::Io { path: path.to_path_buf(), source };
+
+    let mut out = BufWriter::new(File::create(&temp).map_err(io_error)?);
+    write_checkpoint(&mut out, identity, framebuffer, samples).map_err(io_error)?;
+    out.into_inner().map_err(|e| io_error(e.into_error()))?
+        .sync_all().map_err(io_error)?;
+    fs::rename(&temp, path).map_err(io_error)
+}
+
+pub fn load_checkpoint(path: impl AsRef<Path>) -> Result<Checkpoint, CheckpointError> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .map_err(|source| CheckpointError::Io { path: path.to_path_buf(), source })?;
+    read_checkpoint(&mut BufReader::new(file))
+        .map_err(|message| CheckpointError::Format { path: path.to_path_buf(), message })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn identity() -> RenderIdentity {
+        RenderIdentity {
+            scene_hash: fnv1a(b"scene"),
+            seed: 7,
+            sampler: SamplerKind::Sobol,
+            filter: Filter::new(FilterKind::Gaussian),
+            bounces: 4,
+            roulette_depth: 3,
+            img_size: Vec2i { x: 12, y: 8 },
+            region: Rect { x: 2, y: 1, w: 3, h: 2 },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut framebuffer = Framebuffer::for_region(identity().region);
+        for (i, pixel) in framebuffer.pixels.iter_mut().enumerate() {
+            *pixel = Vec3::new(i as f32, 0.1 * i as f32, f32::MAX);
+        }
+        framebuffer.weights[1] = 3.5;
+        let mut bytes = Vec::new();
+        write_checkpoint(&mut bytes, &identity(), &framebuffer, 42).unwrap();
+
+        let checkpoint = read_checkpoint(&mut &bytes[..]).unwrap();
+        assert_eq!(checkpoint.identity, identity());
+        assert_eq!(checkpoint.samples, 42);
+        assert_eq!(checkpoint.framebuffer.origin, Vec2i { x: 2, y: 1 });
+        assert_eq!(checkpoint.framebuffer.pixels, framebuffer.pixels);
+        assert_eq!(checkpoint.framebuffer.weights, framebuffer.weights);
+
+        // anything cut short or tacked on is refused
+        assert!(read_checkpoint(&mut &bytes[..bytes.len() - 1]).is_err());
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(read_checkpoint(&mut &longer[..]).is_err());
+        assert!(read_checkpoint(&mut &b"P6\n"[..]).is_err());
+    }
+
+    #[test]
+    fn identity_mismatch() {
+        assert_eq!(identity().mismatch(&identity()), None);
+        let other = RenderIdentity { seed: 8, ..identity() };
+        assert_eq!(identity().mismatch(&other), Some("seed 7 vs 8".to_string()));
+        let other = RenderIdentity { sampler: SamplerKind::Halton, ..identity() };
+        assert_eq!(identity().mismatch(&other), Some("Sobol sampler vs Halton".to_string()));
+        let other = RenderIdentity { roulette_depth: 5, ..identity() };
+        assert_eq!(identity().mismatch(&other), Some("roulette depth 3 vs 5".to_string()));
+        let other = RenderIdentity { scene_hash: fnv1a(b"other scene"), ..identity() };
+        assert!(identity().mismatch(&other).is_some());
+    }
+
+    #[test]
+    fn fnv1a_known_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,480 @@
+
+// Command line handling for the rustpt binary
+
+use rustpt::output::ImageFormat;
+use rustpt::filter::{self, FilterKind};
+use rustpt::primitives::Rect;
+use rustpt::renderer;
+use rustpt::sampler::SamplerKind;
+use rustpt::tonemap::ToneCurve;
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: rustpt [OPTIONS] [SCENE_FILE]
+
+Renders SCENE_FILE (a TOML scene description), or one of the built-in
+scenes. Options given here override the values in the scene file.
+
+Options:
+  -o, --output <PATH>     Where to write the image, '-' for stdout [default: -]
+      --format <FORMAT>   Image format: ppm (binary), ppm-ascii, png, or pfm
+                          and exr to keep unclipped linear radiance
+                          [default: from the output extension, else ppm]
+      --scene <NAME>      Render a built-in scene instead of a file:
+                          random_world, cornell_box [default: random_world]
+      --width <PIXELS>    Image width. Given alone, the height keeps the scene's aspect ratio
+      --height <PIXELS>   Image height. Given alone, the width keeps the scene's aspect ratio
+  -s, --samples <N>       Samples per pixel
+      --bounces <N>       Maximum bounces per path
+      --roulette-depth <N>
+                          Bounces before Russian roulette may end a path [default: 3]
+      --seed <N>          Seed for the random streams
+      --sampler <NAME>    How each pixel's samples are spread out: independent,
+                          stratified, halton or sobol [default: independent].
+                          Stratified needs to know the sample count up front,
+                          so a --time-limit without --samples falls back to
+                          independent
+      --filter <NAME>     How samples are shared between neighbouring pixels:
+                          box, tent, gaussian or mitchell [default: box]
+      --filter-radius <PIXELS>
+                          How far the filter reaches [default: depends on the filter]
+      --adaptive <ERROR>  Adaptive sampling: stop sampling a pixel once the
+                          relative error of its brightness is below ERROR
+                          (e.g. 0.02), with --samples as the most it can get
+      --min-samples <N>   Samples every pixel gets before adaptive sampling
+                          judges it, needs --adaptive or a scene with
+                          adaptive_threshold [default: 16]
+      --exposure <STOPS>  Brighten (or darken, if negative) before tone mapping
+      --tonemap <CURVE>   How bright values are squeezed into 8-bit output:
+                          clamp, reinhard, filmic, aces
+      --progressive       Render one sample per pixel per pass, refining the
+                          whole image until the sample count is reached
+      --time-limit <SECS> Stop a progressive render after this long. Without
+                          --samples it keeps going until time runs out
+      --save-every <SECS> Write the image so far to the output file this
+                          often during a progressive render
+      --checkpoint <PATH> Save the progressive render's state here now and
+                          then, and when it stops, so it can be resumed
+      --checkpoint-every <SECS>
+                          How often to save the checkpoint [default: 300]
+      --resume            Carry on from the --checkpoint file instead of
+                          starting over
+      --region <X,Y,W,H>  Only render (and write) this rectangle of the image,
+                          measured in pixels from the top left corner
+  -j, --threads <N>       Worker threads [default: one per core]
+      --tile-size <N>     Edge length of the square tiles handed to workers [default: 32]
+  -h, --help              Print this message
+";
+
+#[derive (Copy, Clone, PartialEq, Debug)]
+pub enum BuiltinScene {
+    RandomWorld,
+    CornellBox,
+}
+
+// Where the scene comes from
+#[derive (Clone, PartialEq, Debug)]
+pub enum SceneSource {
+    File(PathBuf),
+    Builtin(BuiltinScene),
+}
+
+#[derive (Clone, PartialEq, Debug)]
+pub struct Options {
+    pub scene: SceneSource,
+    pub output: Option<PathBuf>, // None means stdout
+    pub format: ImageFormat,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub samples: Option<u32>,
+    pub bounces: Option<u32>,
+    pub roulette_depth: Option<u32>,
+    pub seed: Option<u64>,
+    pub sampler: Option<SamplerKind>,
+    pub filter: Option<FilterKind>,
+    pub filter_radius: Option<f32>,
+    pub adaptive_threshold: Option<f32>,
+    pub min_samples: Option<u32>,
+    pub exposure: Option<f32>,
+    pub tone_curve: Option<ToneCurve>,
+    pub region: Option<Rect>, // top-left origin, as given
+    pub progressive: bool, // also switched on by the options below
+    pub time_limit: Option<Duration>,
+    pub save_every: Option<Duration>,
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_every: Duration,
+    pub resume: bool,
+    pub threads: Option<usize>,
+    pub tile_size: i32,
+}
+
+// What the caller should do after parsing
+#[derive (Debug)]
+pub enum Command {
+    Render(Box<Options>),
+    Help,
+}
+
+#[derive (Debug, PartialEq)]
+pub struct CliError(pub String);
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CliError {}
+
+// Parse a number that has to be at least 1
+fn positive<T>(flag: &str, value: &str) -> Result<T, CliError>
+where T: std::str::FromStr + PartialOrd + From<u8> {
+    match value.parse::<T>() {
+        Ok(n) if n >= T::from(1) => Ok(n),
+        Ok(_) => Err(CliError(format!("{} must be at least 1, got {}", flag, value))),
+        Err(_) => Err(CliError(format!("{} expects a positive whole number, got '{}'", flag, value))),
+    }
+}
+
+// "X,Y,W,H", with a positive width and height
+fn parse_region(value: &str) -> Result<Rect, CliError> {
+    let bad = || CliError(format!("--region expects X,Y,WIDTH,HEIGHT, got '{}'", value));
+    let parts = value.split(',')
+        .map(|p| p.trim().parse::<i32>())
+        .collect::<Result<Vec<i32>, _>>()
+        .map_err(|_| bad())?;
+    match parts[..] {
+        [x, y, w, h] if x >= 0 && y >= 0 && w > 0 && h > 0 => Ok(Rect { x, y, w, h }),
+        _ => Err(bad()),
+    }
+}
+
+fn seconds(flag: &str, value: &str) -> Result<Duration, CliError> {
+    value.parse::<f64>().ok()
+        .filter(|s| s.is_finite() && *s > 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| CliError(format!("{} expects a positive number of seconds, got '{}'", flag, value)))
+}
+
+// Parse the arguments, not counting the program name
+pub fn parse_args<I>(args: I) -> Result<Command, CliError>
+where I: IntoIterator<Item = String> {
+    let mut scene_file: Option<PathBuf> = None;
+    let mut builtin: Option<BuiltinScene> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut format: Option<ImageFormat> = None;
+    let mut width = None;
+    let mut height = None;
+    let mut samples = None;
+    let mut bounces = None;
+    let mut roulette_depth = None;
+    let mut seed = None;
+    let mut sampler = None;
+    let mut filter = None;
+    let mut filter_radius = None;
+    let mut adaptive_threshold = None;
+    let mut min_samples = None;
+    let mut exposure = None;
+    let mut tone_curve = None;
+    let mut region = None;
+    let mut progressive = false;
+    let mut time_limit = None;
+    let mut save_every = None;
+    let mut checkpoint = None;
+    let mut checkpoint_every = Duration::from_secs(300);
+    let mut resume = false;
+    let mut threads = None;
+    let mut tile_size = 32;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // accept both "--flag value" and "--flag=value"
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || -> Result<String, CliError> {
+            match inline_value.clone().or_else(|| args.next()) {
+                Some(v) => Ok(v),
+                None => Err(CliError(format!("{} needs a value", flag))),
+            }
+        };
+
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => {
+                let path = value()?;
+                output = if path == "-" { None } else { Some(PathBuf::from(path)) };
+            }
+            "--format" => {
+                let name = value()?;
+                format = Some(ImageFormat::from_name(&name)
+                    .ok_or_else(|| CliError(format!("Unknown output format '{}'", name)))?);
+            }
+            "--scene" => {
+                let name = value()?;
+                builtin = Some(match name.as_str() {
+                    "random_world" => BuiltinScene::RandomWorld,
+                    "cornell_box" => BuiltinScene::CornellBox,
+                    _ => return Err(CliError(format!("Unknown built-in scene '{}'", name))),
+                });
+            }
+            "--width" => width = Some(positive::<i32>("--width", &value()?)?),
+            "--height" => height = Some(positive::<i32>("--height", &value()?)?),
+            "-s" | "--samples" => samples = Some(positive::<u32>("--samples", &value()?)?),
+            "--bounces" => bounces = Some(positive::<u32>("--bounces", &value()?)?),
+            "--roulette-depth" => {
+                let v = value()?;
+                roulette_depth = Some(v.parse::<u32>()
+                    .map_err(|_| CliError(format!("--roulette-depth expects a whole number, got '{}'", v)))?);
+            }
+            "--seed" => {
+                let v = value()?;
+                seed = Some(v.parse::<u64>()
+                    .map_err(|_| CliError(format!("--seed expects a whole number, got '{}'", v)))?);
+            }
+            "--adaptive" => {
+                let v = value()?;
+                adaptive_threshold = Some(v.parse::<f32>().ok().filter(|t| t.is_finite() && *t > 0.0)
+                    .ok_or_else(|| CliError(format!("--adaptive expects a positive error threshold, got '{}'", v)))?);
+            }
+            "--min-samples" => {
+                let n = positive::<u32>("--min-samples", &value()?)?;
+                if n < 2 {
+                    return Err(CliError("--min-samples must be at least 2 to estimate the error".to_string()));
+                }
+                min_samples = Some(n);
+            }
+            "--exposure" => {
+                let v = value()?;
+                exposure = Some(v.parse::<f32>().ok().filter(|e| e.is_finite())
+                    .ok_or_else(|| CliError(format!("--exposure expects a number of stops, got '{}'", v)))?);
+            }
+            "--sampler" => {
+                let name = value()?;
+                sampler = Some(SamplerKind::from_name(&name)
+                    .ok_or_else(|| CliError(format!("Unknown sampler '{}'", name)))?);
+            }
+            "--filter" => {
+                let name = value()?;
+                filter = Some(FilterKind::from_name(&name)
+                    .ok_or_else(|| CliError(format!("Unknown filter '{}'", name)))?);
+            }
+            "--filter-radius" => {
+                let v = value()?;
+                filter_radius = Some(v.parse::<f32>().ok().filter(|r| *r > 0.0 && *r <= filter::MAX_RADIUS)
+                    .ok_or_else(|| CliError(format!(
+                        "--filter-radius expects a number of pixels, above 0 and at most {}, got '{}'",
+                        filter::MAX_RADIUS, v,
+                    )))?);
+            }
+            "--tonemap" => {
+                let name = value()?;
+                tone_curve = Some(ToneCurve::from_name(&name)
+                    .ok_or_else(|| CliError(format!("Unknown tone mapping curve '{}'", name)))?);
+            }
+            "--progressive" => progressive = true,
+            "--time-limit" => time_limit = Some(seconds("--time-limit", &value()?)?),
+            "--save-every" => save_every = Some(seconds("--save-every", &value()?)?),
+            "--checkpoint" => checkpoint = Some(PathBuf::from(value()?)),
+            "--checkpoint-every" => checkpoint_every = seconds("--checkpoint-every", &value()?)?,
+            "--resume" => resume = true,
+            "--region" => region = Some(parse_region(&value()?)?),
+            "-j" | "--threads" => threads = Some(positive::<u32>("--threads", &value()?)? as usize),
+            "--tile-size" => tile_size = positive::<i32>("--tile-size", &value()?)?,
+            _ if flag.starts_with('-') && flag != "-" => {
+                return Err(CliError(format!("Unknown option '{}'", flag)));
+            }
+            _ => {
+                if scene_file.is_some() {
+                    return Err(CliError(format!("Only one scene file can be given, got another: '{}'", arg)));
+                }
+                scene_file = Some(PathBuf::from(arg));
+            }
+        }
+    }
+
+    let scene = match (scene_file, builtin) {
+        (Some(_), Some(_)) => {
+            return Err(CliError("Give either a scene file or --scene, not both".to_string()));
+        }
+        (Some(path), None) => SceneSource::File(path),
+        (None, Some(name)) => SceneSource::Builtin(name),
+        (None, None) => SceneSource::Builtin(BuiltinScene::RandomWorld),
+    };
+
+    if let (Some(w), Some(h)) = (width, height) {
+        if !renderer::image_size_ok(w, h) {
+            return Err(CliError(format!("A {}x{} image is too big, the most is {} pixels", w, h, renderer::MAX_PIXELS)));
+        }
+    }
+    if save_every.is_some() && output.is_none() {
+        return Err(CliError("--save-every needs an output file (-o)".to_string()));
+    }
+    let progressive = progressive || time_limit.is_some() || save_every.is_some() || checkpoint.is_some();
+    if progressive && adaptive_threshold.is_some() {
+        return Err(CliError("Adaptive sampling can't be combined with progressive rendering".to_string()));
+    }
+    // built in scenes have no adaptive settings for --min-samples to tune,
+    // and progressive renders don't sample adaptively at all
+    if min_samples.is_some() && adaptive_threshold.is_none() && (progressive || matches!(scene, SceneSource::Builtin(_))) {
+        return Err(CliError("--min-samples needs adaptive sampling, use --adaptive".to_string()));
+    }
+    if resume && checkpoint.is_none() {
+        return Err(CliError("--resume needs a --checkpoint file to resume from".to_string()));
+    }
+
+    // no explicit format: go by the output file's extension
+    let format = match format {
+        Some(f) => f,
+        None => match output.as_ref().and_then(|p| p.extension()).and_then(|e| e.to_str()) {
+            Some(ext) => ImageFormat::from_name(ext)
+                .ok_or_else(|| CliError(format!("Can't tell the image format from '.{}', use --format", ext)))?,
+            None => ImageFormat::Ppm,
+        },
+    };
+
+    Ok(Command::Render(Box::new(Options {
+        scene,
+        output,
+        format,
+        width,
+        height,
+        samples,
+        bounces,
+        roulette_depth,
+        seed,
+        sampler,
+        filter,
+        filter_radius,
+        adaptive_threshold,
+        min_samples,
+        exposure,
+        tone_curve,
+        region,
+        progressive,
+        time_limit,
+        save_every,
+        checkpoint,
+        checkpoint_every,
+        resume,
+        threads,
+        tile_size,
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, CliError> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    fn options(args: &[&str]) -> Options {
+        match parse(args) {
+            Ok(Command::Render(options)) => *options,
+            other => panic!("Expected options, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let o = options(&[]);
+        assert_eq!(o.scene, SceneSource::Builtin(BuiltinScene::RandomWorld));
+        assert_eq!(o.output, None);
+        assert_eq!(o.format, ImageFormat::Ppm);
+        assert_eq!(o.samples, None);
+        assert_eq!(o.tile_size, 32);
+    }
+
+    #[test]
+    fn all_the_flags() {
+        let o = options(&[
+            "scenes/cornell.toml", "-o", "out.ppm", "--width=200", "--height", "100",
+            "-s", "64", "--bounces", "8", "--roulette-depth", "5", "--seed", "42", "-j", "4", "--tile-size", "16",
+            "--adaptive", "0.02", "--min-samples", "4",
+            "--exposure", "-1.5", "--tonemap", "filmic", "--region", "10,20,30,40",
+            "--sampler", "Sobol", "--filter", "gaussian", "--filter-radius=2",
+        ]);
+        assert_eq!(o.scene, SceneSource::File(PathBuf::from("scenes/cornell.toml")));
+        assert_eq!(o.output, Some(PathBuf::from("out.ppm")));
+        assert_eq!((o.width, o.height), (Some(200), Some(100)));
+        assert_eq!((o.samples, o.bounces, o.seed), (Some(64), Some(8), Some(42)));
+        assert_eq!(o.roulette_depth, Some(5));
+        assert_eq!((o.adaptive_threshold, o.min_samples), (Some(0.02), Some(4)));
+        assert_eq!((o.threads, o.tile_size), (Some(4), 16));
+        assert_eq!((o.exposure, o.tone_curve), (Some(-1.5), Some(ToneCurve::Filmic)));
+        assert_eq!(o.region, Some(Rect { x: 10, y: 20, w: 30, h: 40 }));
+        assert_eq!(o.sampler, Some(SamplerKind::Sobol));
+        assert_eq!((o.filter, o.filter_radius), (Some(FilterKind::Gaussian), Some(2.0)));
+    }
+
+    #[test]
+    fn format_follows_extension() {
+        assert_eq!(options(&["-o", "render.png"]).format, ImageFormat::Png);
+        assert_eq!(options(&["-o", "render.PPM"]).format, ImageFormat::Ppm);
+        assert_eq!(options(&["--format", "ppm-ascii"]).format, ImageFormat::PpmAscii);
+        // an explicit --format wins over the extension
+        assert_eq!(options(&["-o", "render.img", "--format", "png"]).format, ImageFormat::Png);
+    }
+
+    #[test]
+    fn progressive_flags() {
+        assert!(!options(&[]).progressive);
+        assert!(options(&["--progressive"]).progressive);
+
+        let o = options(&["--time-limit", "1.5", "--save-every=10", "-o", "out.png"]);
+        assert!(o.progressive);
+        assert_eq!(o.time_limit, Some(Duration::from_millis(1500)));
+        assert_eq!(o.save_every, Some(Duration::from_secs(10)));
+
+        let o = options(&["--checkpoint", "render.ckpt", "--resume"]);
+        assert!(o.progressive && o.resume);
+        assert_eq!(o.checkpoint, Some(PathBuf::from("render.ckpt")));
+        assert_eq!(o.checkpoint_every, Duration::from_secs(300));
+
+        assert!(parse(&["--resume"]).is_err());
+        assert!(parse(&["--progressive", "--adaptive", "0.05"]).is_err());
+        assert!(parse(&["--save-every", "10"]).is_err());
+        assert!(parse(&["--time-limit", "0"]).is_err());
+    }
+
+    #[test]
+    fn help() {
+        assert!(matches!(parse(&["--samples", "4", "--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert!(parse(&["--width", "0"]).is_err());
+        assert!(parse(&["--width", "70000", "--height", "70000"]).is_err());
+        assert!(parse(&["--width", "16384", "--height", "16384"]).is_ok());
+        assert!(parse(&["--samples", "-3"]).is_err());
+        assert!(parse(&["--threads", "many"]).is_err());
+        assert!(parse(&["--samples"]).is_err());
+        assert!(parse(&["--scene", "teapot"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+        assert!(parse(&["a.toml", "b.toml"]).is_err());
+        assert!(parse(&["a.toml", "--scene", "cornell_box"]).is_err());
+        assert!(parse(&["-o", "image.xyz"]).is_err());
+        assert!(parse(&["--exposure", "inf"]).is_err());
+        assert!(parse(&["--tonemap", "sepia"]).is_err());
+        assert!(parse(&["--sampler", "blue-noise"]).is_err());
+        assert!(parse(&["--filter", "lanczos"]).is_err());
+        assert!(parse(&["--filter-radius", "0"]).is_err());
+        assert!(parse(&["--filter-radius", "100"]).is_err());
+        assert!(parse(&["--adaptive", "0"]).is_err());
+        assert!(parse(&["--min-samples", "1"]).is_err());
+        assert!(parse(&["--min-samples", "8"]).is_err());
+        assert!(parse(&["--min-samples", "8", "--adaptive", "0.05"]).is_ok());
+        assert!(parse(&["scenes/cornell.toml", "--min-samples", "8"]).is_ok());
+        assert!(parse(&["scenes/cornell.toml", "--min-samples", "8", "--progressive"]).is_err());
+        assert!(parse(&["--region", "0,0,10"]).is_err());
+        assert!(parse(&["--region", "0,0,0,10"]).is_err());
+        assert!(parse(&["--region", "-1,0,10,10"]).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+
+// Bare-bones OpenEXR writer: one part, scanlines, no compression, 32-bit
+// float RGB. Every EXR reader understands that much, and it keeps the full
+// linear radiance for whoever picks the image up next.
+
+use std::io::{self, Write};
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const VERSION: [u8; 4] = [2, 0, 0, 0]; // version 2, single part scanline file
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend(name.as_bytes());
+    header.push(0);
+    header.extend(kind.as_bytes());
+    header.push(0);
+    header.extend((value.len() as i32).to_le_bytes());
+    header.extend(value);
+}
+
+fn make_header(width: u32, height: u32) -> Vec<u8> {
+    // channels have to be listed in alphabetical order
+    let mut channels = Vec::new();
+    for name in ["B", "G", "R"] {
+        channels.extend(name.as_bytes());
+        channels.push(0);
+        channels.extend(PIXEL_TYPE_FLOAT.to_le_bytes());
+        channels.extend([0, 0, 0, 0]); // pLinear, then three reserved bytes
+        channels.extend(1i32.to_le_bytes()); // x sampling
+        channels.extend(1i32.to_le_bytes()); // y sampling
+    }
+    channels.push(0);
+
+    let mut window = Vec::new();
+    for v in [0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend(v.to_le_bytes());
+    }
+
+    let mut header = Vec::new();
+    attribute(&mut header, "channels", "chlist", &channels);
+    attribute(&mut header, "compression", "compression", &[0]); // none
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]); // increasing y, top row first
+    attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+    header
+}
+
+// Write a linear RGB image, rows top to bottom, three floats per pixel
+pub fn write_exr(out: &mut impl Write, width: u32, height: u32, rgb: &[f32]) -> io::Result<()> {
+    assert_eq!(rgb.len(), width as usize * height as usize * 3, "Pixel data doesn't match the image size");
+    let header = make_header(width, height);
+    out.write_all(&MAGIC)?;
+    out.write_all(&VERSION)?;
+    out.write_all(&header)?;
+
+    // Uncompressed files hold one scanline per chunk, and the offset table up
+    // front says where each one starts
+    let line_bytes = width as u64 * 3 * 4;
+    let chunk_size = 4 + 4 + line_bytes;
+    let first_chunk = (MAGIC.len() + VERSION.len() + header.len()) as u64 + height as u64 * 8;
+    for y in 0..height as u64 {
+        out.write_all(&(first_chunk + y * chunk_size).to_le_bytes())?;
+    }
+
+    let stride = width as usize * 3;
+    let mut line = Vec::with_capacity(line_bytes as usize);
+    for (y, row) in rgb.chunks(stride).enumerate() {
+        line.clear();
+        // channel by channel (B, G, R), not pixel by pixel
+        for channel in [2, 1, 0] {
+            for pixel in row.chunks(3) {
+                line.extend(pixel[channel].to_le_bytes());
+            }
+        }
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&(line.len() as i32).to_le_bytes())?;
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn i32_at(bytes: &[u8], at: usize) -> i32 {
+        i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn f32_at(bytes: &[u8], at: usize) -> f32 {
+        f32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn exr_layout() {
+        // 2x2, top row red and green, bottom row blue and something bright
+        let rgb = [
+            1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+            0.0, 0.0, 1.0,   12.5, 0.25, 3.0,
+        ];
+        let mut bytes = Vec::new();
+        write_exr(&mut bytes, 2, 2, &rgb).unwrap();
+        assert_eq!(bytes[..4], MAGIC);
+
+        // find the end of the header by skipping over the attributes
+        let mut at = 8;
+        while bytes[at] != 0 {
+            let name_end = at + bytes[at..].iter().position(|&b| b == 0).unwrap();
+            let kind_end = name_end + 1 + bytes[name_end + 1..].iter().position(|&b| b == 0).unwrap();
+            at = kind_end + 5 + i32_at(&bytes, kind_end + 1) as usize;
+        }
+        at += 1;
+
+        let offsets: Vec<usize> = (0..2)
+            .map(|i| u64::from_le_bytes(bytes[at + i * 8..at + i * 8 + 8].try_into().unwrap()) as usize)
+            .collect();
+        assert_eq!(offsets[0], at + 16);
+        assert_eq!(offsets[1], offsets[0] + 8 + 24);
+        assert_eq!(offsets[1] + 8 + 24, bytes.len());
+
+        // second scanline, channels stored B then G then R
+        let line = offsets[1];
+        assert_eq!(i32_at(&bytes, line), 1);
+        assert_eq!(i32_at(&bytes, line + 4), 24);
+        let values: Vec<f32> = (0..6).map(|i| f32_at(&bytes, line + 8 + i * 4)).collect();
+        assert_eq!(values, [1.0, 3.0, 0.0, 0.25, 0.0, 12.5]);
+    }
+}
@@ -0,0 +1,149 @@
+
+// Reconstruction filters
+//
+// Each sample lands somewhere inside its pixel and gets spread over the
+// pixels around it, weighted by the filter. A pixel ends up as the weighted
+// average of every sample near it. Wider filters trade some sharpness for
+// smoother edges.
+
+use crate::primitives::Vec2f;
+
+use serde::Deserialize;
+
+#[derive (Copy, Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterKind {
+    // every sample counts the same, only for the pixel it landed in
+    #[default]
+    Box,
+    // falls off linearly from the middle of the pixel
+    Tent,
+    // a Gaussian shifted down so it reaches zero at the radius
+    Gaussian,
+    // Mitchell-Netravali with B = C = 1/3. Sharper than the Gaussian, at the
+    // cost of a little ringing from its negative lobes
+    Mitchell,
+}
+
+impl FilterKind {
+    pub fn from_name(name: &str) -> Option<FilterKind> {
+        match name.to_ascii_lowercase().as_str() {
+            "box" => Some(FilterKind::Box),
+            "tent" | "triangle" => Some(FilterKind::Tent),
+            "gaussian" => Some(FilterKind::Gaussian),
+            "mitchell" => Some(FilterKind::Mitchell),
+            _ => None,
+        }
+    }
+
+    // The usual size for each, in pixels
+    pub fn default_radius(&self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+        }
+    }
+}
+
+// Anything wider makes for enormous tile margins, and a blurry picture
+pub const MAX_RADIUS: f32 = 8.0;
+
+#[derive (Copy, Clone, PartialEq, Debug)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: f32, // in pixels, along each axis
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(FilterKind::Box)
+    }
+}
+
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b)) / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        0.0
+    }
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind) -> Filter {
+        Filter { kind, radius: kind.default_radius() }
+    }
+
+    // How many pixels past its own a sample can reach
+    pub fn margin(&self) -> i32 {
+        (self.radius - 0.5).ceil().max(0.0) as i32
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let r = self.radius;
+        match self.kind {
+            // half open, so a sample on the line between two pixels only
+            // goes to one of them
+            FilterKind::Box => if -r <= x && x < r { 1.0 } else { 0.0 },
+            FilterKind::Tent => (1.0 - x.abs() / r).max(0.0),
+            FilterKind::Gaussian => {
+                let sigma = r / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(r)).max(0.0)
+            }
+            // the curve is defined out to 2, stretch it over the radius
+            FilterKind::Mitchell => mitchell(2.0 * x / r),
+        }
+    }
+
+    // Weight of a sample offset from a pixel's centre by this much
+    pub fn evaluate(&self, offset: Vec2f) -> f32 {
+        self.evaluate_1d(offset.x) * self.evaluate_1d(offset.y)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KINDS: [FilterKind; 4] = [FilterKind::Box, FilterKind::Tent, FilterKind::Gaussian, FilterKind::Mitchell];
+
+    #[test]
+    fn zero_past_the_radius() {
+        for kind in KINDS {
+            let filter = Filter::new(kind);
+            let r = filter.radius;
+            assert!(filter.evaluate(Vec2f::new(0.0, 0.0)) > 0.0, "{:?}", kind);
+            assert_eq!(filter.evaluate(Vec2f::new(r, 0.0)), 0.0, "{:?}", kind);
+            assert_eq!(filter.evaluate(Vec2f::new(0.0, -r - 0.1)), 0.0, "{:?}", kind);
+            // symmetric
+            let a = filter.evaluate(Vec2f::new(0.3, -0.2));
+            assert!((a - filter.evaluate(Vec2f::new(-0.3, 0.2))).abs() < 1e-6, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn mitchell_has_negative_lobes() {
+        let filter = Filter::new(FilterKind::Mitchell);
+        assert!((filter.evaluate_1d(0.0) - 8.0 / 9.0).abs() < 1e-6);
+        assert!(filter.evaluate_1d(1.5) < 0.0);
+        // the pieces meet up at x = 1 (half the radius)
+        assert!((filter.evaluate_1d(0.9999) - filter.evaluate_1d(1.0001)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn margins() {
+        let margins: Vec<i32> = KINDS.iter().map(|&kind| Filter::new(kind).margin()).collect();
+        assert_eq!(margins, [0, 1, 1, 2]);
+    }
+}
@@ -0,0 +1,22 @@
+// The original code's explicit returns and spelled out fields stay as they
+// were written, test literals included, rather than being churned to suit
+// clippy's style lints
+#![allow(clippy::needless_return, clippy::unused_unit, clippy::redundant_field_names, clippy::excessive_precision)]
+
+pub mod primitives;
+pub mod scene;
+pub mod renderer;
+pub mod thread_utils;
+pub mod bvh;
+pub mod mesh;
+pub mod obj;
+pub mod scene_file;
+pub mod png;
+pub mod output;
+pub mod exr;
+pub mod tonemap;
+pub mod checkpoint;
+pub mod sampler;
+pub mod filter;
+pub mod texture;
+pub mod noise;
@@ -1,28 +1,69 @@
+mod cli;
 
-mod primitives;
-mod scene;
-mod renderer;
-
-use crate::primitives::{
+use rustpt::primitives::{
+    Rect,
     Vec2i,
     Vec3,
 };
-use crate::scene::{
-    Camera,
+use rustpt::scene::{
+    Background,
     Scene
 };
 
-use crate::renderer::{
-    Tile,
+use rustpt::checkpoint::{
+    RenderIdentity,
+    fnv1a,
+    load_checkpoint,
+    save_checkpoint,
+};
+
+use rustpt::renderer::{
+    AdaptiveProperties,
+    Framebuffer,
+    MAX_PIXELS,
     RenderProperties,
+    image_size_ok,
+};
+
+use rustpt::output::{
+    save_image,
+    write_image,
+};
+
+use rustpt::scene_file::{
+    SceneFile,
+    load_scene,
+};
+
+use rustpt::filter::Filter;
+use rustpt::sampler::SamplerKind;
+use rustpt::tonemap::ToneMap;
+
+use rustpt::thread_utils::{
+    ProgressiveProperties,
+    ThreadProperties,
+    render_progressive,
+    render_region,
+    traced_region,
+};
+
+use cli::{
+    BuiltinScene,
+    CliError,
+    Command,
+    Options,
+    SceneSource,
 };
 
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
 use rand::SeedableRng;
 use rand::rngs::SmallRng;
 
-
-fn main() {
-    // image
+// Scenes that ship with the binary, at the book's default 3:2 image size
+fn builtin_scene(which: BuiltinScene) -> SceneFile {
     let aspect_ratio = 3.0 / 2.0;
     let image = Vec2i {
         x: 400,
@@ -31,49 +72,286 @@ fn main() {
 
     let render_config = RenderProperties {
         samples: 10,
-        bounces: 50
+        bounces: 50,
+        roulette_depth: 3,
+        seed: 0,
+        adaptive: None,
+        sampler: SamplerKind::Independent,
+        filter: Filter::default(),
     };
 
-    // random generator
-    let mut small_rng = SmallRng::seed_from_u64(0);
-
-    // Scene (now includes camera)
-    let scene = Scene {
-        camera: Camera::new(
-            Vec3::new(13.0, 2.0, 3.0), // lookfrom
-            Vec3::zero(), // lookat
-            Vec3::new(0.0, 1.0, 0.0), // vup
-            20.0,
-            aspect_ratio, 
-            0.1, // aperture
-            10.0, // dist_to_focus
+    // random generator (only used to build the scene, rendering has its own)
+    let mut small_rng = SmallRng::seed_from_u64(render_config.seed);
+
+    let scene = match which {
+        BuiltinScene::RandomWorld => Scene::new(
+            Scene::random_world_camera(aspect_ratio),
+            Scene::random_world(&mut small_rng, None, false).into_bvh(0.0, 1.0),
+            Background::Sky,
         ),
-        world: Scene::random_world(&mut small_rng)
+        BuiltinScene::CornellBox => Scene::new(
+            Scene::cornell_camera(aspect_ratio),
+            Scene::cornell_box().into_bvh(0.0, 1.0),
+            Background::Solid { color: Vec3::zero() },
+        ),
+    };
+    SceneFile {
+        scene,
+        properties: render_config,
+        image_size: image,
+        tone_map: ToneMap::default(),
+        files: Vec::new(),
+    }
+}
+
+// Fold the command line overrides into whatever the scene asked for. Fails
+// if the image comes out too big, which can't be known until the scene's
+// aspect ratio is.
+fn apply_options(scene_file: &mut SceneFile, options: &Options) -> Result<(), CliError> {
+    let aspect_ratio = scene_file.scene.camera.aspect_ratio();
+    let size = &mut scene_file.image_size;
+    match (options.width, options.height) {
+        (Some(w), Some(h)) => {
+            *size = Vec2i { x: w, y: h };
+            // widen or narrow the view to fit, rather than stretch the picture
+            scene_file.scene.camera = scene_file.scene.camera.with_aspect_ratio(w as f32 / h as f32);
+        }
+        (Some(w), None) => *size = Vec2i { x: w, y: ((w as f32 / aspect_ratio) as i32).max(1) },
+        (None, Some(h)) => *size = Vec2i { x: ((h as f32 * aspect_ratio) as i32).max(1), y: h },
+        (None, None) => (),
+    }
+    if !image_size_ok(size.x, size.y) {
+        return Err(CliError(format!(
+            "A {}x{} image is too big, the most is {} pixels", size.x, size.y, MAX_PIXELS,
+        )));
+    }
+
+    let props = &mut scene_file.properties;
+    props.samples = options.samples.unwrap_or(props.samples);
+    props.bounces = options.bounces.unwrap_or(props.bounces);
+    props.roulette_depth = options.roulette_depth.unwrap_or(props.roulette_depth);
+    props.seed = options.seed.unwrap_or(props.seed);
+    props.sampler = options.sampler.unwrap_or(props.sampler);
+    if let Some(kind) = options.filter {
+        props.filter = Filter::new(kind);
+    }
+    props.filter.radius = options.filter_radius.unwrap_or(props.filter.radius);
+    if let Some(threshold) = options.adaptive_threshold {
+        let min_samples = props.adaptive.map_or(16, |a| a.min_samples);
+        props.adaptive = Some(AdaptiveProperties { min_samples, threshold });
+    }
+    if let Some(min_samples) = options.min_samples {
+        let adaptive = props.adaptive.as_mut().ok_or_else(|| {
+            CliError("--min-samples needs adaptive sampling, use --adaptive or set adaptive_threshold in the scene".to_string())
+        })?;
+        adaptive.min_samples = min_samples;
+    }
+    if options.progressive && props.adaptive.is_some() {
+        eprintln!("Adaptive sampling doesn't apply to progressive renders, every pixel gets the same samples");
+        props.adaptive = None;
+    }
+    // a time limit with no sample count means "as many as fit in the time"
+    if options.time_limit.is_some() && options.samples.is_none() {
+        props.samples = u32::MAX;
+        // strata that many to a side would be no better than random
+        if props.sampler == SamplerKind::Stratified {
+            eprintln!("Stratified sampling needs a sample count, using independent samples for this time limited render");
+            props.sampler = SamplerKind::Independent;
+        }
+    }
+
+    let tone_map = &mut scene_file.tone_map;
+    tone_map.exposure = options.exposure.unwrap_or(tone_map.exposure);
+    tone_map.curve = options.tone_curve.unwrap_or(tone_map.curve);
+    Ok(())
+}
+
+// Write to the file named on the command line, or stdout when there isn't one
+fn save(options: &Options, framebuffer: &Framebuffer, tone_map: &ToneMap) -> io::Result<()> {
+    match &options.output {
+        Some(path) => save_image(path, framebuffer, options.format, tone_map),
+        None => write_image(&mut BufWriter::new(io::stdout().lock()), framebuffer, options.format, tone_map),
+    }
+}
+
+// Identifies the scene for checkpoints. Files are hashed by content, so
+// editing the scene, or any OBJ, MTL or image it reads, invalidates old
+// checkpoints. files are the ones the scene pulled in. A file that can't be
+// read is an error, rather than hashing like an empty one.
+fn scene_fingerprint(source: &SceneSource, files: &[PathBuf]) -> io::Result<u64> {
+    let read = |path: &Path| {
+        std::fs::read(path)
+            .map(|bytes| fnv1a(&bytes))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    };
+    let scene = match source {
+        SceneSource::File(path) => read(path)?,
+        SceneSource::Builtin(BuiltinScene::RandomWorld) => fnv1a(b"builtin random_world"),
+        SceneSource::Builtin(BuiltinScene::CornellBox) => fnv1a(b"builtin cornell_box"),
+    };
+    // textures are loaded in no particular order
+    let mut files = files.to_vec();
+    files.sort();
+    files.dedup();
+    let mut hashes = scene.to_le_bytes().to_vec();
+    for path in &files {
+        hashes.extend(read(path)?.to_le_bytes());
+    }
+    Ok(fnv1a(&hashes))
+}
+
+// Swap the new image in whole, so anything watching the file never sees
+// half of one
+fn save_snapshot(path: &Path, options: &Options, framebuffer: &Framebuffer, tone_map: &ToneMap) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    save_image(&temp, framebuffer, options.format, tone_map)?;
+    std::fs::rename(&temp, path)
+}
+
+fn main() {
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(Command::Render(options)) => *options,
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\nTry 'rustpt --help' for more information.", e);
+            std::process::exit(2);
+        }
     };
-    
+
+    let mut scene_file = match &options.scene {
+        SceneSource::File(path) => match load_scene(path) {
+            Ok(scene_file) => scene_file,
+            Err(e) => {
+                eprintln!("Couldn't load scene: {}", e);
+                std::process::exit(1);
+            }
+        },
+        SceneSource::Builtin(which) => builtin_scene(*which),
+    };
+    if let Err(e) = apply_options(&mut scene_file, &options) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+    let SceneFile { scene, properties: render_config, image_size: image, tone_map, files } = scene_file;
+
+    let thread_config = ThreadProperties {
+        threads: options.threads.unwrap_or_else(ThreadProperties::default_threads),
+        tile_size: Vec2i { x: options.tile_size, y: options.tile_size },
+    };
+
+    // The region is given from the top left, but the framebuffer counts rows
+    // from the bottom
+    let fits = |start: i32, len: i32, limit: i32| start.checked_add(len).is_some_and(|end| end <= limit);
+    let region = match options.region {
+        Some(r) if fits(r.x, r.w, image.x) && fits(r.y, r.h, image.y) => Rect { y: image.y - r.y - r.h, ..r },
+        Some(_) => {
+            eprintln!("The region doesn't fit inside the {}x{} image", image.x, image.y);
+            std::process::exit(2);
+        }
+        None => Rect { x: 0, y: 0, w: image.x, h: image.y },
+    };
+
     // render
-    // The render loop should now be a job submission mechanism
-    // Iterate lines, submitting them as tasks to the thread.
-	println!("P3\n{} {}\n255", image.x, image.y);
-    // TILE BASED RENDERER
-    // let tile = Tile::render_tile(
-    //     Rect { x: 0, y: 0, w: image.x, h: image.y },
-    //     image,
-    //     &scene,
-    //     &render_config,
-    //     &mut small_rng
-    // );
-    // for pixel in tile.pixels.iter().rev() {
-    //     println!("{}", pixel.print_ppm(render_config.samples));
-    // }
-
-    // LINE BASED RENDERER
-    for row in (0..image.y).rev() {
-        let tile = Tile::render_line(row, image, &scene, &render_config, &mut small_rng);
-        eprintln!("Printing scanline #{}", row);
-        for pixel in tile.pixels {
-            println!("{}", pixel.print_ppm(render_config.samples))
+    let sample_count = match render_config.samples {
+        u32::MAX => "as many samples as time allows".to_string(),
+        n => format!("{} samples", n),
+    };
+    eprintln!(
+        "Rendering {}x{} at {} with {} threads",
+        region.w, region.h, sample_count, thread_config.threads
+    );
+    let framebuffer = if options.progressive {
+        let scene_hash = scene_fingerprint(&options.scene, &files).unwrap_or_else(|e| {
+            eprintln!("Couldn't fingerprint the scene for checkpoints: {}", e);
+            std::process::exit(1);
+        });
+        let identity = RenderIdentity {
+            scene_hash,
+            seed: render_config.seed,
+            sampler: render_config.sampler,
+            filter: render_config.filter,
+            bounces: render_config.bounces,
+            roulette_depth: render_config.roulette_depth,
+            img_size: image,
+            region,
+        };
+        let (start, start_samples) = match &options.checkpoint {
+            Some(path) if options.resume => {
+                let checkpoint = load_checkpoint(path).unwrap_or_else(|e| {
+                    eprintln!("Couldn't resume: {}", e);
+                    std::process::exit(1);
+                });
+                if let Some(difference) = checkpoint.identity.mismatch(&identity) {
+                    eprintln!("Can't resume from {}, it's for a different render: {}", path.display(), difference);
+                    std::process::exit(1);
+                }
+                eprintln!("Resuming from {} samples", checkpoint.samples);
+                (checkpoint.framebuffer, checkpoint.samples)
+            }
+            Some(path) if path.exists() => {
+                eprintln!("{} already exists. Use --resume to carry on from it, or remove it to start over", path.display());
+                std::process::exit(1);
+            }
+            _ => (Framebuffer::for_region(region), 0),
+        };
+
+        let write_checkpoint = |path: &Path, framebuffer: &Framebuffer, samples: u32| {
+            match save_checkpoint(path, &identity, framebuffer, samples) {
+                Ok(()) => eprintln!("Checkpointed {} samples to {}", samples, path.display()),
+                Err(e) => eprintln!("Couldn't write the checkpoint: {}", e),
+            }
+        };
+        let mut last_save = Instant::now();
+        let mut last_checkpoint = Instant::now();
+        let started = Instant::now();
+        let (framebuffer, samples) = render_progressive(
+            &scene, &render_config, &thread_config, image, start, start_samples,
+            &ProgressiveProperties { time_limit: options.time_limit },
+            |framebuffer, samples| {
+                eprintln!("Finished pass {} ({:.1?})", samples, started.elapsed());
+                if let (Some(interval), Some(path)) = (options.save_every, &options.output) {
+                    if last_save.elapsed() >= interval {
+                        match save_snapshot(path, &options, framebuffer, &tone_map) {
+                            Ok(()) => eprintln!("Saved {} samples to {}", samples, path.display()),
+                            Err(e) => eprintln!("Couldn't write the image so far: {}", e),
+                        }
+                        last_save = Instant::now();
+                    }
+                }
+                if let Some(path) = &options.checkpoint {
+                    if last_checkpoint.elapsed() >= options.checkpoint_every {
+                        write_checkpoint(path, framebuffer, samples);
+                        last_checkpoint = Instant::now();
+                    }
+                }
+            },
+        );
+        // only the time limit stops a render short
+        if samples < render_config.samples {
+            eprintln!("Out of time after {} samples", samples);
+        }
+        // one last time, so a finished render can be extended later
+        if let Some(path) = &options.checkpoint {
+            write_checkpoint(path, &framebuffer, samples);
         }
+        framebuffer
+    } else {
+        render_region(&scene, &render_config, &thread_config, image, region)
+    };
+
+    if render_config.adaptive.is_some() {
+        // the samples include the pixels traced for the filter's margin
+        let traced = traced_region(region, &render_config, image);
+        let average = framebuffer.samples as f64 / (traced.w as f64 * traced.h as f64);
+        eprintln!("Used {:.1} samples per pixel on average", average);
+    }
+    if let Err(e) = save(&options, &framebuffer, &tone_map) {
+        eprintln!("Couldn't write the image: {}", e);
+        std::process::exit(1);
     }
     eprintln!("Done!");
 }
@@ -0,0 +1,321 @@
+
+use crate::primitives::{Vec2f, Vec3, Ray, Aabb};
+use crate::scene::{Hittable, HitRecord, Material};
+
+use std::sync::Arc;
+
+// Indexed triangle mesh. Vertex attributes live in shared buffers and each
+// face picks three of them by index. Normals and UVs are optional, but when
+// present there must be one per position.
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2f>,
+    pub indices: Vec<[usize; 3]>,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<Vec2f>,
+        indices: Vec<[usize; 3]>,
+    ) -> TriangleMesh {
+        assert!(normals.is_empty() || normals.len() == positions.len(), "Need one normal per vertex");
+        assert!(uvs.is_empty() || uvs.len() == positions.len(), "Need one UV per vertex");
+        assert!(
+            indices.iter().flatten().all(|&i| i < positions.len()),
+            "Face index out of range"
+        );
+        TriangleMesh { positions, normals, uvs, indices }
+    }
+
+    // Turn the mesh into one Triangle per face, all sharing this mesh. The
+    // result is a plain list; put it through into_bvh() (alone or as part of
+    // a bigger world) before rendering anything sizeable.
+    pub fn into_hittable(self, material: Material) -> Hittable {
+        let faces = self.indices.len();
+        let mesh = Arc::new(self);
+        Hittable::HittableList {
+            hittables: (0..faces).map(|face| Hittable::Triangle {
+                mesh: mesh.clone(),
+                face,
+                material: material.clone(),
+            }).collect()
+        }
+    }
+
+    fn vertices(&self, face: usize) -> (Vec3, Vec3, Vec3) {
+        let [i0, i1, i2] = self.indices[face];
+        (self.positions[i0], self.positions[i1], self.positions[i2])
+    }
+
+    pub fn face_area(&self, face: usize) -> f32 {
+        let (v0, v1, v2) = self.vertices(face);
+        Vec3::cross(v1 - v0, v2 - v0).length() * 0.5
+    }
+
+    // Unit normal of the flat face, following the winding order
+    pub fn face_normal(&self, face: usize) -> Vec3 {
+        let (v0, v1, v2) = self.vertices(face);
+        Vec3::as_unit(Vec3::cross(v1 - v0, v2 - v0))
+    }
+
+    // Uniformly distributed point on a face, from two uniform numbers in [0, 1)
+    pub fn sample_face(&self, face: usize, r1: f32, r2: f32) -> Vec3 {
+        let (v0, v1, v2) = self.vertices(face);
+        let su = r1.sqrt();
+        let b0 = 1.0 - su;
+        let b1 = r2 * su;
+        v0 * b0 + v1 * b1 + v2 * (1.0 - b0 - b1)
+    }
+
+    pub fn face_bounds(&self, face: usize) -> Aabb {
+        let (v0, v1, v2) = self.vertices(face);
+        Aabb::new(v0, v1).grow(v2)
+    }
+
+    // Möller–Trumbore ray/triangle intersection
+    pub fn hit_face<'a>(
+        &self,
+        face: usize,
+        r: Ray,
+        t_min: f32,
+        t_max: f32,
+        material: &'a Material,
+    ) -> Option<HitRecord<'a>> {
+        let (v0, v1, v2) = self.vertices(face);
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
+
+        let pvec = Vec3::cross(r.dir, edge2);
+        let det = Vec3::dot(edge1, pvec);
+        if det.abs() < 1e-9 {
+            return None; // ray is parallel to the triangle (or it's degenerate)
+        }
+        let inv_det = 1.0 / det;
+
+        let tvec = r.orig - v0;
+        let b1 = Vec3::dot(tvec, pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = Vec3::cross(tvec, edge1);
+        let b2 = Vec3::dot(r.dir, qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = Vec3::dot(edge2, qvec) * inv_det;
+        if t < t_min || t > t_max {
+            return None;
+        }
+        let b0 = 1.0 - b1 - b2;
+        let [i0, i1, i2] = self.indices[face];
+
+        // without per-vertex UVs, fall back to the barycentric coordinates
+        let (uv, dpdu, dpdv) = if self.uvs.is_empty() {
+            (Vec2f::new(b1, b2), edge1, edge2)
+        } else {
+            let (uv0, uv1, uv2) = (self.uvs[i0], self.uvs[i1], self.uvs[i2]);
+            let uv = Vec2f::new(
+                b0 * uv0.x + b1 * uv1.x + b2 * uv2.x,
+                b0 * uv0.y + b1 * uv1.y + b2 * uv2.y,
+            );
+            // solve edge = dp/du * du + dp/dv * dv for both edges
+            let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            if det.abs() < 1e-12 {
+                (uv, edge1, edge2) // UVs all in a line, nothing to go on
+            } else {
+                (
+                    uv,
+                    (edge1 * duv2.y - edge2 * duv1.y) / det,
+                    (edge2 * duv1.x - edge1 * duv2.x) / det,
+                )
+            }
+        };
+
+        // Per-vertex normals, when given, say which way is "out". The true
+        // surface normal is flipped to agree with them and decides which side
+        // was hit; the smoothed normal is then used for shading.
+        let mut outward_normal = Vec3::as_unit(Vec3::cross(edge1, edge2));
+        let blended = if self.normals.is_empty() {
+            Vec3::zero()
+        } else {
+            self.normals[i0] * b0 + self.normals[i1] * b1 + self.normals[i2] * b2
+        };
+        // normals pointing opposite ways can cancel out, which leaves only
+        // the face normal to go on
+        let shading_normal = if blended.length_squared() == 0.0 {
+            None
+        } else {
+            let n = Vec3::as_unit(blended);
+            if Vec3::dot(n, outward_normal) < 0.0 {
+                outward_normal = -outward_normal;
+            }
+            Some(n)
+        };
+
+        let mut record = HitRecord {
+            p: r.at(t),
+            normal: outward_normal,
+            material,
+            t,
+            front_face: false,
+            uv,
+            tangent: Vec3::zero(),
+            bitangent: Vec3::zero(),
+            medium: None,
+        };
+        record.set_face_normal(r, outward_normal);
+        record.set_tangents(shading_normal.unwrap_or(outward_normal), dpdu, dpdv);
+        if let Some(n) = shading_normal {
+            record.normal = if record.front_face { n } else { -n };
+        }
+        Some(record)
+    }
+}
+
+impl Hittable {
+    // Parallelogram with one corner at q and sides u and v
+    pub fn quad(q: Vec3, u: Vec3, v: Vec3, material: Material) -> Hittable {
+        TriangleMesh::new(
+            vec![q, q + u, q + u + v, q + v],
+            vec![],
+            vec![Vec2f::new(0.0, 0.0), Vec2f::new(1.0, 0.0), Vec2f::new(1.0, 1.0), Vec2f::new(0.0, 1.0)],
+            vec![[0, 1, 2], [0, 2, 3]],
+        ).into_hittable(material)
+    }
+
+    // Box sitting on the plane y = corner.y, spun by angle degrees about the
+    // vertical axis through corner
+    pub fn cuboid(corner: Vec3, size: Vec3, angle: f32, material: Material) -> Hittable {
+        let (sin, cos) = crate::scene::degrees_to_radians(angle).sin_cos();
+        let rotate = |p: Vec3| corner + Vec3::new(cos * p.x + sin * p.z, p.y, -sin * p.x + cos * p.z);
+        let positions = (0..8).map(|i| rotate(Vec3::new(
+            if i & 1 == 0 { 0.0 } else { size.x },
+            if i & 2 == 0 { 0.0 } else { size.y },
+            if i & 4 == 0 { 0.0 } else { size.z },
+        ))).collect();
+        // two triangles for each side, wound to face outward
+        let indices = vec![
+            [0, 2, 3], [0, 3, 1], // -z
+            [4, 5, 7], [4, 7, 6], // +z
+            [0, 4, 6], [0, 6, 2], // -x
+            [1, 3, 7], [1, 7, 5], // +x
+            [0, 1, 5], [0, 5, 4], // -y
+            [2, 6, 7], [2, 7, 3], // +y
+        ];
+        TriangleMesh::new(positions, vec![], vec![], indices).into_hittable(material)
+    }
+
+    // A single free-standing triangle
+    pub fn triangle(v0: Vec3, v1: Vec3, v2: Vec3, material: Material) -> Hittable {
+        Hittable::Triangle {
+            mesh: Arc::new(TriangleMesh::new(vec![v0, v1, v2], vec![], vec![], vec![[0, 1, 2]])),
+            face: 0,
+            material,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::texture::Texture;
+
+    fn grey() -> Material {
+        Material::Lambertian { albedo: Texture::solid(Vec3::ones() * 0.5) }
+    }
+
+    fn toward_z(x: f32, y: f32) -> Ray {
+        Ray { orig: Vec3::new(x, y, -1.0), dir: Vec3::new(0.0, 0.0, 1.0), time: 0.0 }
+    }
+
+    #[test]
+    fn triangle_hit_and_miss() {
+        let tri = Hittable::triangle(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            grey(),
+        );
+        let rec = tri.hit(toward_z(0.25, 0.25), 0.001, f32::INFINITY).expect("should hit");
+        assert_eq!(rec.t, 1.0);
+        assert_eq!(rec.p, Vec3::new(0.25, 0.25, 0.0));
+        // counter-clockwise winding faces +z, so a ray travelling +z hits the back
+        assert!(!rec.front_face);
+        assert_eq!(rec.normal, Vec3::new(0.0, 0.0, -1.0));
+
+        assert!(tri.hit(toward_z(0.75, 0.75), 0.001, f32::INFINITY).is_none());
+        assert!(tri.hit(toward_z(0.25, 0.25), 0.001, 0.5).is_none());
+    }
+
+    #[test]
+    fn mesh_interpolates_attributes() {
+        let mesh = TriangleMesh::new(
+            vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)],
+            vec![Vec3::new(0.0, 0.0, -1.0); 3],
+            vec![Vec2f::new(0.0, 0.0), Vec2f::new(1.0, 0.0), Vec2f::new(0.0, 1.0)],
+            vec![[0, 1, 2]],
+        );
+        let material = grey();
+        let rec = mesh.hit_face(0, toward_z(0.5, 0.25), 0.001, f32::INFINITY, &material)
+            .expect("should hit");
+        assert_eq!(rec.uv, Vec2f::new(0.5, 0.25));
+        assert_eq!(rec.normal, Vec3::new(0.0, 0.0, -1.0));
+        // u runs along x and v along y, whichever way the face points
+        assert_eq!(rec.tangent, Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(rec.bitangent, Vec3::new(0.0, 1.0, 0.0));
+
+        // vertex normals that cancel out where the ray lands leave the face normal
+        let mesh = TriangleMesh::new(
+            mesh.positions.clone(),
+            vec![Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0)],
+            vec![],
+            vec![[0, 1, 2]],
+        );
+        let rec = mesh.hit_face(0, toward_z(0.5, 0.25), 0.001, f32::INFINITY, &material)
+            .expect("should hit");
+        assert_eq!(rec.normal, Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn mesh_shares_vertices() {
+        // a unit quad: two faces, four shared vertices
+        let mesh = TriangleMesh::new(
+            vec![
+                Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0),
+            ],
+            vec![],
+            vec![],
+            vec![[0, 1, 2], [0, 2, 3]],
+        );
+        let quad = mesh.into_hittable(grey());
+        assert!(quad.hit(toward_z(0.9, 0.1), 0.001, f32::INFINITY).is_some());
+        assert!(quad.hit(toward_z(0.1, 0.9), 0.001, f32::INFINITY).is_some());
+        assert_eq!(
+            quad.bounding_box(0.0, 1.0),
+            Some(Aabb::new(Vec3::zero(), Vec3::new(1.0, 1.0, 0.0)))
+        );
+    }
+
+    #[test]
+    fn cuboid_faces_point_out() {
+        let cube = Hittable::cuboid(Vec3::zero(), Vec3::ones(), 30.0, grey());
+        // where the rotation carries the middle of the unit cube
+        let (sin, cos) = crate::scene::degrees_to_radians(30.0).sin_cos();
+        let center = Vec3::new(0.5 * (cos + sin), 0.5, 0.5 * (cos - sin));
+        for dir in [
+            Vec3::new(1.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0),
+        ] {
+            // a ray fired inward from outside should strike the front of a face
+            let ray = Ray { orig: center - dir * 5.0, dir, time: 0.0 };
+            let rec = cube.hit(ray, 0.001, f32::INFINITY).expect("should hit the cube");
+            assert!(rec.front_face);
+        }
+    }
+}
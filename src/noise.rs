@@ -0,0 +1,179 @@
+
+// Procedural noise, for textures that don't come from a bitmap
+//
+// Ken Perlin's improved gradient noise: a shuffled table picks one of twelve
+// gradients at each lattice point, and a quintic curve blends between them.
+// Sums of it at rising frequencies give fBm and turbulence, which the marble
+// and wood patterns are built on.
+
+use crate::primitives::Vec3;
+
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0],
+];
+
+pub struct Perlin {
+    // 0..256 shuffled, twice over so lookups never need wrapping
+    perm: [u8; 512],
+}
+
+impl std::fmt::Debug for Perlin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Perlin")
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+impl Perlin {
+    // The same seed always gives the same noise
+    pub fn new(seed: u64) -> Perlin {
+        let mut table: Vec<u8> = (0..=255).collect();
+        table.shuffle(&mut SmallRng::seed_from_u64(seed));
+        let mut perm = [0; 512];
+        for (i, p) in perm.iter_mut().enumerate() {
+            *p = table[i % 256];
+        }
+        Perlin { perm }
+    }
+
+    fn hash(&self, x: usize, y: usize, z: usize) -> usize {
+        self.perm[self.perm[self.perm[x] as usize + y] as usize + z] as usize
+    }
+
+    // Smooth noise, roughly in -1..1 and zero at every lattice point
+    pub fn noise(&self, p: Vec3) -> f32 {
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+        let cell = |f: f32| (f as i64).rem_euclid(256) as usize;
+        let (i, j, k) = (cell(fx), cell(fy), cell(fz));
+
+        let grad = |di: usize, dj: usize, dk: usize| {
+            let g = GRADIENTS[self.hash(i + di, j + dj, k + dk) % 12];
+            g[0] * (x - di as f32) + g[1] * (y - dj as f32) + g[2] * (z - dk as f32)
+        };
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        lerp(w,
+            lerp(v, lerp(u, grad(0, 0, 0), grad(1, 0, 0)), lerp(u, grad(0, 1, 0), grad(1, 1, 0))),
+            lerp(v, lerp(u, grad(0, 0, 1), grad(1, 0, 1)), lerp(u, grad(0, 1, 1), grad(1, 1, 1))),
+        )
+    }
+
+    // Fractal Brownian motion: octaves of noise, each at twice the frequency
+    // and half the strength of the one before
+    pub fn fbm(&self, p: Vec3, octaves: u32) -> f32 {
+        let (mut sum, mut weight, mut p) = (0.0, 1.0, p);
+        for _ in 0..octaves {
+            sum += weight * self.noise(p);
+            weight *= 0.5;
+            p *= 2.0;
+        }
+        sum
+    }
+
+    // Like fbm but summing the size of the noise, which gives creases where
+    // it crosses zero
+    pub fn turbulence(&self, p: Vec3, octaves: u32) -> f32 {
+        let (mut sum, mut weight, mut p) = (0.0, 1.0, p);
+        for _ in 0..octaves {
+            sum += weight * self.noise(p).abs();
+            weight *= 0.5;
+            p *= 2.0;
+        }
+        sum
+    }
+}
+
+// What a noise texture does with the noise
+#[derive (Copy, Clone, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoisePattern {
+    Fbm,
+    Turbulence,
+    // veins running across z, bent around by turbulence
+    Marble,
+    // rings around the y axis, wobbled by fbm
+    Wood,
+}
+
+impl NoisePattern {
+    // Where p falls between the texture's two colors, 0..1
+    pub fn value(&self, perlin: &Perlin, p: Vec3, octaves: u32) -> f32 {
+        let t = match self {
+            NoisePattern::Fbm => 0.5 * (1.0 + perlin.fbm(p, octaves)),
+            NoisePattern::Turbulence => perlin.turbulence(p, octaves),
+            NoisePattern::Marble => 0.5 * (1.0 + (p.z + 10.0 * perlin.turbulence(p, octaves)).sin()),
+            NoisePattern::Wood => {
+                let rings = p.x.hypot(p.z) + 0.5 * perlin.fbm(p * 0.5, octaves);
+                // sharper at the outside edge of each ring, like the real thing
+                (rings - rings.floor()).powi(3)
+            }
+        };
+        t.clamp(0.0, 1.0)
+    }
+
+    // Colors for 0 and 1 when a scene doesn't pick its own
+    pub fn default_colors(&self) -> (Vec3, Vec3) {
+        match self {
+            NoisePattern::Fbm | NoisePattern::Turbulence => (Vec3::zero(), Vec3::ones()),
+            NoisePattern::Marble => (Vec3::new(0.2, 0.2, 0.22), Vec3::new(0.95, 0.94, 0.9)),
+            NoisePattern::Wood => (Vec3::new(0.55, 0.35, 0.17), Vec3::new(0.32, 0.18, 0.07)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn noise_is_smooth_and_seeded() {
+        let perlin = Perlin::new(7);
+        // zero on the lattice
+        assert_eq!(perlin.noise(Vec3::new(3.0, -2.0, 5.0)), 0.0);
+
+        let mut rng = SmallRng::seed_from_u64(1);
+        let distrib = rand::distributions::Uniform::new(-20.0, 20.0);
+        let (mut low, mut high) = (0.0f32, 0.0f32);
+        for _ in 0..2000 {
+            let p = Vec3::rand(&mut rng, distrib);
+            let n = perlin.noise(p);
+            low = low.min(n);
+            high = high.max(n);
+            // no jumps between close points
+            assert!((n - perlin.noise(p + Vec3::new(1e-3, 0.0, 0.0))).abs() < 0.01);
+        }
+        assert!(low < -0.5 && high > 0.5 && low >= -1.1 && high <= 1.1, "{} to {}", low, high);
+
+        let p = Vec3::new(0.3, 1.7, -4.2);
+        assert_eq!(Perlin::new(7).noise(p), perlin.noise(p));
+        assert_ne!(Perlin::new(8).noise(p), perlin.noise(p));
+    }
+
+    #[test]
+    fn patterns_stay_in_range() {
+        let perlin = Perlin::new(0);
+        for pattern in [NoisePattern::Fbm, NoisePattern::Turbulence, NoisePattern::Marble, NoisePattern::Wood] {
+            let values: Vec<f32> = (0..500)
+                .map(|i| pattern.value(&perlin, Vec3::new(i as f32 * 0.37, i as f32 * 0.11, i as f32 * -0.23), 7))
+                .collect();
+            assert!(values.iter().all(|t| (0.0..=1.0).contains(t)), "{:?}", pattern);
+            // and not stuck at one end
+            let mean = values.iter().sum::<f32>() / values.len() as f32;
+            assert!(mean > 0.05 && mean < 0.95, "{:?} averages {}", pattern, mean);
+        }
+    }
+}
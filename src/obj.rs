@@ -0,0 +1,460 @@
+
+// Wavefront OBJ/MTL import
+//
+// Supports the parts of the format that describe polygon meshes: positions,
+// normals, texture coordinates, faces (triangulated as fans), materials and
+// material libraries. Statements for curves, lines, groups and smoothing are
+// skipped.
+
+use crate::primitives::{Vec2f, Vec3};
+use crate::scene::{Hittable, Material};
+use crate::mesh::TriangleMesh;
+use crate::texture::Texture;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::SplitWhitespace;
+
+// Used for faces that come before any usemtl statement
+const DEFAULT_MATERIAL: Material = Material::Lambertian {
+    albedo: Texture::Solid { color: Vec3 { x: 0.8, y: 0.8, z: 0.8 } },
+};
+
+#[derive (Debug)]
+pub enum ObjError {
+    Io { path: PathBuf, source: io::Error },
+    Parse { file: String, line: usize, message: String },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ObjError::Parse { file, line, message } => write!(f, "{}:{}: {}", file, line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+// Line-by-line cursor that knows where it is, for error messages
+struct LineParser<'a> {
+    file: &'a str,
+    line: usize,
+}
+
+impl LineParser<'_> {
+    fn error(&self, message: String) -> ObjError {
+        ObjError::Parse { file: self.file.to_string(), line: self.line, message }
+    }
+
+    fn float(&self, token: Option<&str>, what: &str) -> Result<f32, ObjError> {
+        let token = token.ok_or_else(|| self.error(format!("Missing {}", what)))?;
+        // NaN and infinity parse fine, but no geometry or color can use them
+        token.parse::<f32>().ok().filter(|x| x.is_finite())
+            .ok_or_else(|| self.error(format!("Invalid number '{}' for {}", token, what)))
+    }
+
+    fn vec3(&self, tokens: &mut SplitWhitespace, what: &str) -> Result<Vec3, ObjError> {
+        Ok(Vec3::new(
+            self.float(tokens.next(), what)?,
+            self.float(tokens.next(), what)?,
+            self.float(tokens.next(), what)?,
+        ))
+    }
+
+    // OBJ indices are 1-based, and negative ones count back from the newest element
+    fn index(&self, token: &str, count: usize, what: &str) -> Result<usize, ObjError> {
+        let raw = token.parse::<i64>()
+            .map_err(|_| self.error(format!("Invalid {} index '{}'", what, token)))?;
+        let resolved = if raw > 0 { raw - 1 } else { count as i64 + raw };
+        if raw == 0 || resolved < 0 || resolved >= count as i64 {
+            return Err(self.error(format!(
+                "{} index {} out of range ({} defined so far)", what, raw, count
+            )));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+// Turn MTL parameters into the closest Material we have.
+//
+// Anything with an emissive color is a light. Anything see-through (dissolve
+// below 1 or a refracting illum model) is glass.
+// A specular color that outweighs the diffuse one, or the reflection-only
+// illum model, makes a metal whose fuzz comes from the specular exponent.
+// Everything else is Lambertian.
+#[derive (Clone)]
+struct MtlParams {
+    diffuse: Vec3,
+    specular: Vec3,
+    emission: Vec3,
+    exponent: f32,
+    ior: f32,
+    dissolve: f32,
+    illum: u32,
+}
+
+impl MtlParams {
+    fn new() -> MtlParams {
+        MtlParams {
+            diffuse: Vec3::ones() * 0.8,
+            specular: Vec3::zero(),
+            emission: Vec3::zero(),
+            exponent: 0.0,
+            ior: 1.5,
+            dissolve: 1.0,
+            illum: 2,
+        }
+    }
+
+    fn to_material(&self) -> Material {
+        let luminance = |c: Vec3| 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+        if !self.emission.near_zero() {
+            Material::DiffuseLight { emit: Texture::solid(self.emission) }
+        } else if self.dissolve < 1.0 || matches!(self.illum, 4 | 6 | 7 | 9) {
+            Material::Dielectric { index_refraction: self.ior }
+        } else if !self.specular.near_zero()
+            && (self.illum == 3 || luminance(self.specular) > luminance(self.diffuse)) {
+            // Phong exponent to a rough 0..1 roughness, 0 being a perfect mirror
+            let fuzz = (2.0 / (self.exponent + 2.0)).sqrt().clamp(0.0, 1.0);
+            Material::Metal { albedo: Texture::solid(self.specular), fuzz: Texture::constant(fuzz) }
+        } else {
+            Material::Lambertian { albedo: Texture::solid(self.diffuse) }
+        }
+    }
+}
+
+// Parse an MTL library into named materials
+pub fn parse_mtl(source: &str, file: &str) -> Result<HashMap<String, Material>, ObjError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlParams)> = None;
+    let mut parser = LineParser { file, line: 0 };
+
+    for (number, line) in source.lines().enumerate() {
+        parser.line = number + 1;
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) => k,
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            let name = tokens.next()
+                .ok_or_else(|| parser.error("newmtl without a name".to_string()))?;
+            if let Some((name, params)) = current.take() {
+                materials.insert(name, params.to_material());
+            }
+            current = Some((name.to_string(), MtlParams::new()));
+            continue;
+        }
+
+        let params = match current.as_mut() {
+            Some((_, params)) => params,
+            None => return Err(parser.error(format!("'{}' before any newmtl", keyword))),
+        };
+        match keyword {
+            "Kd" => params.diffuse = parser.vec3(&mut tokens, "diffuse color")?,
+            "Ks" => params.specular = parser.vec3(&mut tokens, "specular color")?,
+            "Ke" => params.emission = parser.vec3(&mut tokens, "emissive color")?,
+            "Ns" => params.exponent = parser.float(tokens.next(), "specular exponent")?,
+            "Ni" => params.ior = parser.float(tokens.next(), "index of refraction")?,
+            "d" => params.dissolve = parser.float(tokens.next(), "dissolve")?,
+            "Tr" => params.dissolve = 1.0 - parser.float(tokens.next(), "transparency")?,
+            "illum" => {
+                let token = tokens.next()
+                    .ok_or_else(|| parser.error("Missing illumination model".to_string()))?;
+                params.illum = token.parse()
+                    .map_err(|_| parser.error(format!("Invalid illumination model '{}'", token)))?;
+            }
+            _ => {} // texture maps and the like aren't supported, skip them
+        }
+    }
+    if let Some((name, params)) = current {
+        materials.insert(name, params.to_material());
+    }
+    Ok(materials)
+}
+
+// One mesh gets built per material, since a mesh carries a single Material
+struct MeshBuilder {
+    material: Material,
+    positions: Vec<Vec3>,
+    normals: Vec<Option<Vec3>>,
+    uvs: Vec<Option<Vec2f>>,
+    indices: Vec<[usize; 3]>,
+    // (position, uv, normal) index triple from the file => vertex in this mesh
+    vertex_map: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+}
+
+impl MeshBuilder {
+    fn new(material: Material) -> MeshBuilder {
+        MeshBuilder {
+            material,
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            indices: Vec::new(),
+            vertex_map: HashMap::new(),
+        }
+    }
+
+    fn build(self) -> Hittable {
+        let (positions, normals, uvs, indices) = (self.positions, self.normals, self.uvs, self.indices);
+        // Vertices that came without a normal or UV get a stand-in, but only
+        // if some other vertex in the mesh has one. Missing normals take the
+        // normal of the first face that used the vertex.
+        let normals = if normals.iter().any(|n| n.is_some()) {
+            let mut face_normals = vec![Vec3::zero(); positions.len()];
+            for &[i0, i1, i2] in indices.iter().rev() {
+                let n = Vec3::cross(positions[i1] - positions[i0], positions[i2] - positions[i0]);
+                let n = if n.near_zero() { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::as_unit(n) };
+                for i in [i0, i1, i2] {
+                    face_normals[i] = n;
+                }
+            }
+            normals.iter().zip(face_normals).map(|(n, fallback)| n.unwrap_or(fallback)).collect()
+        } else {
+            Vec::new()
+        };
+        let uvs = if uvs.iter().any(|uv| uv.is_some()) {
+            uvs.iter().map(|uv| uv.unwrap_or(Vec2f::zero())).collect()
+        } else {
+            Vec::new()
+        };
+        TriangleMesh::new(positions, normals, uvs, indices).into_hittable(self.material)
+    }
+}
+
+// Parse OBJ source. `file` is only used in error messages. Material libraries
+// named by mtllib are handed to `load_library`, which should return the
+// materials they define.
+pub fn parse_obj<F>(source: &str, file: &str, mut load_library: F) -> Result<Hittable, ObjError>
+where F: FnMut(&str) -> Result<HashMap<String, Material>, ObjError> {
+    let mut parser = LineParser { file, line: 0 };
+
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<Vec2f> = Vec::new();
+
+    let mut library: HashMap<String, Material> = HashMap::new();
+    let mut meshes: Vec<MeshBuilder> = vec![MeshBuilder::new(DEFAULT_MATERIAL)];
+    let mut mesh_by_name: HashMap<String, usize> = HashMap::new();
+    let mut current = 0;
+
+    for (number, line) in source.lines().enumerate() {
+        parser.line = number + 1;
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) => k,
+            None => continue,
+        };
+
+        match keyword {
+            "v" => positions.push(parser.vec3(&mut tokens, "vertex position")?),
+            "vn" => {
+                let n = parser.vec3(&mut tokens, "vertex normal")?;
+                // normalizing these would make NaNs
+                if n.length_squared() == 0.0 {
+                    return Err(parser.error(format!("Vertex normal {} {} {} has no direction", n.x, n.y, n.z)));
+                }
+                normals.push(Vec3::as_unit(n));
+            }
+            "vt" => uvs.push(Vec2f::new(
+                parser.float(tokens.next(), "texture coordinate")?,
+                // 1D texture coordinates are allowed, v defaults to 0
+                tokens.next().map_or(Ok(0.0), |t| parser.float(Some(t), "texture coordinate"))?,
+            )),
+            "f" => {
+                let mut corners = Vec::new();
+                for corner in tokens {
+                    let mut parts = corner.split('/');
+                    let v = parser.index(parts.next().unwrap_or(""), positions.len(), "Vertex")?;
+                    let vt = match parts.next() {
+                        Some("") | None => None,
+                        Some(t) => Some(parser.index(t, uvs.len(), "Texture coordinate")?),
+                    };
+                    let vn = match parts.next() {
+                        Some("") | None => None,
+                        Some(t) => Some(parser.index(t, normals.len(), "Normal")?),
+                    };
+                    let mesh = &mut meshes[current];
+                    let next_index = mesh.positions.len();
+                    let index = *mesh.vertex_map.entry((v, vt, vn)).or_insert_with(|| {
+                        mesh.positions.push(positions[v]);
+                        mesh.uvs.push(vt.map(|i| uvs[i]));
+                        mesh.normals.push(vn.map(|i| normals[i]));
+                        next_index
+                    });
+                    corners.push(index);
+                }
+                if corners.len() < 3 {
+                    return Err(parser.error(format!(
+                        "Face needs at least 3 vertices, found {}", corners.len()
+                    )));
+                }
+                // fan triangulation, fine for the convex polygons DCC tools export
+                for i in 1..corners.len() - 1 {
+                    meshes[current].indices.push([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            "mtllib" => {
+                for name in tokens {
+                    library.extend(load_library(name)?);
+                }
+            }
+            "usemtl" => {
+                let name = tokens.next()
+                    .ok_or_else(|| parser.error("usemtl without a material name".to_string()))?;
+                let material = library.get(name)
+                    .ok_or_else(|| parser.error(format!("Unknown material '{}'", name)))?
+                    .clone();
+                current = *mesh_by_name.entry(name.to_string()).or_insert_with(|| {
+                    meshes.push(MeshBuilder::new(material));
+                    meshes.len() - 1
+                });
+            }
+            _ => {} // o, g, s, l and friends don't change the geometry we build
+        }
+    }
+
+    Ok(Hittable::HittableList {
+        hittables: meshes.into_iter()
+            .filter(|mesh| !mesh.indices.is_empty())
+            .map(MeshBuilder::build)
+            .collect()
+    })
+}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    fs::read_to_string(path).map_err(|source| ObjError::Io { path: path.to_path_buf(), source })
+}
+
+// Load an OBJ file and any material libraries it refers to (looked up
+// relative to the OBJ file). The result is a list of meshes, one per material.
+// The paths of the files read are added to `files`.
+pub fn load_obj(path: impl AsRef<Path>, files: &mut Vec<PathBuf>) -> Result<Hittable, ObjError> {
+    let path = path.as_ref();
+    let source = read_file(path)?;
+    files.push(path.to_path_buf());
+    let directory = path.parent().unwrap_or(Path::new(""));
+    parse_obj(&source, &path.display().to_string(), |name| {
+        let mtl_path = directory.join(name);
+        let mtl_source = read_file(&mtl_path)?;
+        files.push(mtl_path.clone());
+        parse_mtl(&mtl_source, &mtl_path.display().to_string())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Ray;
+
+    const MTL: &str = "
+# two materials
+newmtl red
+Kd 0.8 0.1 0.1
+
+newmtl mirror
+Kd 0.0 0.0 0.0
+Ks 0.9 0.9 0.9
+Ns 1000
+illum 3
+
+newmtl glass
+Ni 1.33
+d 0.1
+
+newmtl lamp
+Ke 4 4 3
+";
+
+    fn no_library(name: &str) -> Result<HashMap<String, Material>, ObjError> {
+        panic!("Unexpected mtllib {}", name)
+    }
+
+    fn parse_error(source: &str) -> ObjError {
+        match parse_obj(source, "bad.obj", no_library) {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => e,
+        }
+    }
+
+    fn count_triangles(h: &Hittable) -> usize {
+        match h {
+            Hittable::HittableList { hittables } => hittables.iter().map(count_triangles).sum(),
+            Hittable::Triangle { .. } => 1,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn mtl_maps_to_materials() {
+        let materials = parse_mtl(MTL, "test.mtl").unwrap();
+        assert!(matches!(materials["red"], Material::Lambertian { albedo: Texture::Solid { color } } if color == Vec3::new(0.8, 0.1, 0.1)));
+        assert!(matches!(materials["mirror"], Material::Metal { fuzz: Texture::Solid { color }, .. } if color.x < 0.1));
+        assert!(matches!(materials["glass"], Material::Dielectric { index_refraction } if index_refraction == 1.33));
+        assert!(matches!(materials["lamp"], Material::DiffuseLight { emit: Texture::Solid { color } } if color == Vec3::new(4.0, 4.0, 3.0)));
+    }
+
+    #[test]
+    fn obj_quad_with_material() {
+        let source = "
+mtllib scene.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+usemtl red
+f 1/1/1 2/2/1 3/3/1 4/4/1
+f -4 -2 -1
+";
+        let world = parse_obj(source, "test.obj", |name| {
+            assert_eq!(name, "scene.mtl");
+            parse_mtl(MTL, name)
+        }).unwrap();
+        assert_eq!(count_triangles(&world), 3);
+
+        let ray = Ray { orig: Vec3::new(0.75, 0.25, 1.0), dir: Vec3::new(0.0, 0.0, -1.0), time: 0.0 };
+        let rec = world.hit(ray, 0.001, f32::INFINITY).expect("should hit the quad");
+        assert_eq!(rec.uv, Vec2f::new(0.75, 0.25));
+        assert!(matches!(rec.material, Material::Lambertian { .. }));
+    }
+
+    #[test]
+    fn obj_errors_report_lines() {
+        let err = parse_error("v 0 0 0\nv 1 0 0\nf 1 2 3\n");
+        assert!(matches!(err, ObjError::Parse { line: 3, .. }), "{}", err);
+
+        let err = parse_error("v 0 0\n");
+        assert!(matches!(err, ObjError::Parse { line: 1, .. }), "{}", err);
+
+        let err = parse_error("v 0 0 zero\n");
+        assert_eq!(err.to_string(), "bad.obj:1: Invalid number 'zero' for vertex position");
+
+        let err = parse_error("\n\nusemtl nothing\n");
+        assert_eq!(err.to_string(), "bad.obj:3: Unknown material 'nothing'");
+
+        let err = parse_error("v 0 0 0\nvn 0 0 0\n");
+        assert_eq!(err.to_string(), "bad.obj:2: Vertex normal 0 0 0 has no direction");
+        let err = parse_error("vn 0 nan 1\n");
+        assert!(matches!(err, ObjError::Parse { line: 1, .. }), "{}", err);
+        let err = parse_error("v 0 0 0\nv nan 0 0\n");
+        assert_eq!(err.to_string(), "bad.obj:2: Invalid number 'nan' for vertex position");
+        let err = parse_error("v inf 0 0\n");
+        assert!(matches!(err, ObjError::Parse { line: 1, .. }), "{}", err);
+        let err = parse_error("\nvt 0.5 -inf\n");
+        assert!(matches!(err, ObjError::Parse { line: 2, .. }), "{}", err);
+    }
+}
@@ -0,0 +1,198 @@
+
+// Turning a finished framebuffer into an image file
+
+use crate::exr;
+use crate::png;
+use crate::renderer::Framebuffer;
+use crate::tonemap::ToneMap;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive (Copy, Clone, PartialEq, Debug)]
+pub enum ImageFormat {
+    Ppm, // binary P6
+    PpmAscii, // plain text P3, several times bigger
+    Png,
+    // floating point, linear radiance with nothing clipped
+    Pfm,
+    Exr,
+}
+
+impl ImageFormat {
+    // Accepts both format names and file extensions ("png", "PNG", ...)
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "ppm" => Some(ImageFormat::Ppm),
+            "ppm-ascii" => Some(ImageFormat::PpmAscii),
+            "png" => Some(ImageFormat::Png),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" => Some(ImageFormat::Exr),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Option<ImageFormat> {
+        path.as_ref().extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(ImageFormat::from_name)
+    }
+}
+
+// Tone mapped 8-bit sRGB triples, top row first, the way nearly every file
+// format wants them
+pub fn to_rgb8(framebuffer: &Framebuffer, tone_map: &ToneMap) -> Vec<u8> {
+    framebuffer.rows_top_down()
+        .flatten()
+        .flat_map(|pixel| tone_map.to_rgb8(pixel))
+        .collect()
+}
+
+// Linear RGB floats, top row first. Just the average of the samples, no
+// gamma and no clamping.
+pub fn to_rgb_f32(framebuffer: &Framebuffer) -> Vec<f32> {
+    framebuffer.rows_top_down()
+        .flatten()
+        .flat_map(|pixel| [pixel.x, pixel.y, pixel.z])
+        .collect()
+}
+
+// Binary PPM, written out a row at a time
+pub fn write_ppm(out: &mut impl Write, framebuffer: &Framebuffer, tone_map: &ToneMap) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", framebuffer.size.x, framebuffer.size.y)?;
+    let mut line = Vec::with_capacity(framebuffer.size.x as usize * 3);
+    for row in framebuffer.rows_top_down() {
+        line.clear();
+        line.extend(row.flat_map(|pixel| tone_map.to_rgb8(pixel)));
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+// Plain text PPM, one pixel per line
+pub fn write_ppm_ascii(out: &mut impl Write, framebuffer: &Framebuffer, tone_map: &ToneMap) -> io::Result<()> {
+    writeln!(out, "P3\n{} {}\n255", framebuffer.size.x, framebuffer.size.y)?;
+    for row in framebuffer.rows_top_down() {
+        for pixel in row {
+            let [r, g, b] = tone_map.to_rgb8(pixel);
+            writeln!(out, "{} {} {}", r, g, b)?;
+        }
+    }
+    Ok(())
+}
+
+// Portable float map. Rows run bottom to top, same as the framebuffer, and
+// the negative scale marks the floats as little-endian.
+pub fn write_pfm(out: &mut impl Write, framebuffer: &Framebuffer) -> io::Result<()> {
+    write!(out, "PF\n{} {}\n-1.0\n", framebuffer.size.x, framebuffer.size.y)?;
+    for i in 0..framebuffer.pixels.len() {
+        let pixel = framebuffer.average(i);
+        for v in [pixel.x, pixel.y, pixel.z] {
+            out.write_all(&v.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// The tone map only applies to the 8-bit formats. PFM and EXR get the plain
+// linear radiance so it can be exposed and mapped again later.
+pub fn write_image(
+    out: &mut impl Write,
+    framebuffer: &Framebuffer,
+    format: ImageFormat,
+    tone_map: &ToneMap,
+) -> io::Result<()> {
+    match format {
+        ImageFormat::Ppm => write_ppm(out, framebuffer, tone_map)?,
+        ImageFormat::PpmAscii => write_ppm_ascii(out, framebuffer, tone_map)?,
+        ImageFormat::Png => png::write_png(
+            out,
+            framebuffer.size.x as u32,
+            framebuffer.size.y as u32,
+            &to_rgb8(framebuffer, tone_map),
+        )?,
+        ImageFormat::Pfm => write_pfm(out, framebuffer)?,
+        ImageFormat::Exr => exr::write_exr(
+            out,
+            framebuffer.size.x as u32,
+            framebuffer.size.y as u32,
+            &to_rgb_f32(framebuffer),
+        )?,
+    }
+    out.flush()
+}
+
+pub fn save_image(
+    path: impl AsRef<Path>,
+    framebuffer: &Framebuffer,
+    format: ImageFormat,
+    tone_map: &ToneMap,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_image(&mut out, framebuffer, format, tone_map)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::{Vec2i, Vec3};
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(ImageFormat::from_path("render.png"), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_path("out/Render.PPM"), Some(ImageFormat::Ppm));
+        assert_eq!(ImageFormat::from_path("render.exr"), Some(ImageFormat::Exr));
+        assert_eq!(ImageFormat::from_path("render.jpg"), None);
+        assert_eq!(ImageFormat::from_path("render"), None);
+    }
+
+    #[test]
+    fn rgb8_is_top_down() {
+        // bottom row black, top row white
+        let mut framebuffer = Framebuffer::new(Vec2i { x: 2, y: 2 });
+        framebuffer.pixels[2] = Vec3::ones();
+        framebuffer.pixels[3] = Vec3::ones();
+        framebuffer.weights = vec![1.0; 4];
+        assert_eq!(to_rgb8(&framebuffer, &ToneMap::default()), [255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn pfm_keeps_radiance() {
+        let mut framebuffer = Framebuffer::new(Vec2i { x: 2, y: 1 });
+        framebuffer.pixels[1] = Vec3::new(40.0, 2.0, 0.5);
+        framebuffer.weights = vec![4.0; 2];
+        let mut bytes = Vec::new();
+        write_pfm(&mut bytes, &framebuffer).unwrap();
+
+        let header = b"PF\n2 1\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let values: Vec<f32> = bytes[header.len()..].chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, [0.0, 0.0, 0.0, 10.0, 0.5, 0.125]);
+    }
+
+    #[test]
+    fn ppm_binary_and_text_agree() {
+        let mut framebuffer = Framebuffer::new(Vec2i { x: 2, y: 1 });
+        framebuffer.pixels[0] = Vec3::new(1.0, 0.5, 0.0);
+        framebuffer.pixels[1] = Vec3::new(0.25, 1.0, 0.75);
+        framebuffer.weights = vec![1.0; 2];
+        let tone_map = ToneMap::default();
+
+        let mut binary = Vec::new();
+        write_ppm(&mut binary, &framebuffer, &tone_map).unwrap();
+        let header = b"P6\n2 1\n255\n";
+        assert_eq!(&binary[..header.len()], header);
+        let pixels = &binary[header.len()..];
+        assert_eq!(pixels, to_rgb8(&framebuffer, &tone_map));
+
+        let mut text = Vec::new();
+        write_ppm_ascii(&mut text, &framebuffer, &tone_map).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let values: Vec<u8> = text.split_whitespace().skip(4).map(|v| v.parse().unwrap()).collect();
+        assert!(text.starts_with("P3\n2 1\n255\n"));
+        assert_eq!(values, pixels);
+    }
+}
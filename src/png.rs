@@ -0,0 +1,824 @@
+
+// A small, dependency free PNG encoder. Pixels are filtered per row, then
+// squeezed with LZ77 and the fixed Huffman codes from the deflate spec. It's
+// not going to beat libpng, but it gets most of the way there on renders.
+//
+// There's a decoder too, for loading textures. It takes anything the spec
+// allows except interlaced images.
+
+use std::io::{self, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// CRC-32 as used by PNG chunks (and zip, and gzip...)
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0xffff_ffff, bytes) ^ 0xffff_ffff
+}
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |c, &b| CRC_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8))
+}
+
+pub fn adler32(bytes: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the most bytes that can be summed before b could overflow
+    for chunk in bytes.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+// Deflate streams pack bits starting from the least significant end of each byte
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes: Vec::new(), buffer: 0, count: 0 }
+    }
+
+    fn write_bits(&mut self, value: u32, bits: u32) {
+        self.buffer |= value << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go out most significant bit first, unlike everything else
+    fn write_code(&mut self, code: u32, bits: u32) {
+        let reversed = code.reverse_bits() >> (32 - bits);
+        self.write_bits(reversed, bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+// Literal/length symbol in the fixed Huffman code
+fn write_fixed_symbol(out: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => out.write_code(0x30 + symbol, 8),
+        144..=255 => out.write_code(0x190 + symbol - 144, 9),
+        256..=279 => out.write_code(symbol - 256, 7),
+        _ => out.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(out: &mut BitWriter, length: usize, distance: usize) {
+    // the tables are short enough that a scan beats anything clever
+    let l = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+    write_fixed_symbol(out, 257 + l as u16);
+    out.write_bits((length - LENGTH_BASE[l] as usize) as u32, LENGTH_EXTRA[l] as u32);
+
+    let d = DIST_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+    out.write_code(d as u32, 5);
+    out.write_bits((distance - DIST_BASE[d] as usize) as u32, DIST_EXTRA[d] as u32);
+}
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+// How many earlier occurrences to check before settling for the best so far
+const MAX_CHAIN: usize = 64;
+
+fn hash3(bytes: &[u8]) -> usize {
+    let v = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (v.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+// Raw deflate data (no zlib header) as a single fixed Huffman block
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::new();
+    out.write_bits(1, 1); // last block
+    out.write_bits(1, 2); // fixed Huffman codes
+
+    // head[h] is the latest position with hash h, prev[i % WINDOW] the one
+    // before i. Chains stop at the window, so a slot is only reused once the
+    // position it held is too far back to match
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let insert = |pos: usize, head: &mut [usize], prev: &mut [usize]| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash3(&data[pos..]);
+            prev[pos & (WINDOW - 1)] = head[h];
+            head[h] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        if pos + MIN_MATCH <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash3(&data[pos..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW && chain < MAX_CHAIN {
+                let len = data[candidate..].iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate & (WINDOW - 1)];
+                chain += 1;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            write_match(&mut out, best_len, best_dist);
+            for p in pos..pos + best_len {
+                insert(p, &mut head, &mut prev);
+            }
+            pos += best_len;
+        } else {
+            write_fixed_symbol(&mut out, data[pos] as u16);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    write_fixed_symbol(&mut out, 256); // end of block
+    out.finish()
+}
+
+// Deflate data wrapped in a zlib header and checksum, as PNG wants it
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01]; // 32K window, no dictionary, "fastest"
+    out.extend(deflate(data));
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Filter each scanline with whichever of the five PNG filters leaves the
+// smallest values, the usual "minimum sum of absolute differences" guess.
+fn filter_rows(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    const BPP: usize = 3;
+    let stride = width * BPP;
+    let zero_row = vec![0u8; stride];
+    let mut out = Vec::with_capacity((stride + 1) * height);
+    let mut candidate = vec![0u8; stride];
+    let mut best = vec![0u8; stride];
+
+    for y in 0..height {
+        let row = &rgb[y * stride..(y + 1) * stride];
+        let above = if y == 0 { &zero_row[..] } else { &rgb[(y - 1) * stride..y * stride] };
+
+        let mut best_filter = 0;
+        let mut best_score = u64::MAX;
+        for filter in 0..5u8 {
+            for i in 0..stride {
+                let a = if i >= BPP { row[i - BPP] } else { 0 };
+                let b = above[i];
+                let c = if i >= BPP { above[i - BPP] } else { 0 };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                candidate[i] = row[i].wrapping_sub(predicted);
+            }
+            let score = candidate.iter().map(|&v| (v as i8).unsigned_abs() as u64).sum();
+            if score < best_score {
+                best_score = score;
+                best_filter = filter;
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+        out.push(best_filter);
+        out.extend_from_slice(&best);
+    }
+    out
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32_update(crc32_update(0xffff_ffff, kind), data) ^ 0xffff_ffff;
+    out.write_all(&crc.to_be_bytes())
+}
+
+// Write an 8-bit RGB image, rows top to bottom, three bytes per pixel
+pub fn write_png(out: &mut impl Write, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    assert_eq!(rgb.len(), width as usize * height as usize * 3, "Pixel data doesn't match the image size");
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    header.extend([
+        8, // bits per channel
+        2, // colour type: truecolour
+        0, // compression: deflate
+        0, // filtering: adaptive
+        0, // not interlaced
+    ]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    let filtered = filter_rows(rgb, width as usize, height as usize);
+    write_chunk(out, b"IDAT", &zlib_compress(&filtered))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+// Reading deflate streams back, least significant bit first. Running off the
+// end feeds in zeros, which is caught afterwards by overrun(), so the
+// Huffman decoder can always look a few bits ahead.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn new(bytes: &[u8]) -> BitReader<'_> {
+        BitReader { bytes, at: 0, buffer: 0, count: 0 }
+    }
+
+    fn fill(&mut self, bits: u32) {
+        while self.count < bits {
+            let byte = self.bytes.get(self.at).copied().unwrap_or(0);
+            self.at += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+    }
+
+    fn peek(&mut self, bits: u32) -> u32 {
+        self.fill(bits);
+        self.buffer & ((1 << bits) - 1)
+    }
+
+    fn consume(&mut self, bits: u32) {
+        self.buffer >>= bits;
+        self.count -= bits;
+    }
+
+    fn bits(&mut self, bits: u32) -> u32 {
+        let value = self.peek(bits);
+        self.consume(bits);
+        value
+    }
+
+    // Skip to the next whole byte, for stored blocks
+    fn align(&mut self) {
+        self.consume(self.count % 8);
+    }
+
+    // Bytes read so far, not counting any still sitting in the buffer
+    fn position(&self) -> usize {
+        self.at - (self.count / 8) as usize
+    }
+
+    fn overrun(&self) -> bool {
+        self.position() > self.bytes.len()
+    }
+}
+
+// Codes up to this long are decoded with a single table lookup
+const FAST_BITS: u32 = 9;
+
+// Canonical Huffman code, from the code length of each symbol
+struct Huffman {
+    // indexed by the next FAST_BITS of input: symbol and code length, or a
+    // length of 0 for codes that are longer
+    fast: Vec<(u16, u8)>,
+    counts: [u16; 16],  // how many codes there are of each length
+    symbols: Vec<u16>,  // symbols in code order
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // more codes of some length than there's room for
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err("over-subscribed Huffman code".to_string());
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        let mut next_code = [0u32; 16];
+        let mut code = 0;
+        for len in 1..16 {
+            code = (code + counts[len - 1] as u32) << 1;
+            next_code[len] = code;
+        }
+        let mut fast = vec![(0u16, 0u8); 1 << FAST_BITS];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            symbols[offsets[len as usize] as usize] = symbol as u16;
+            offsets[len as usize] += 1;
+
+            let code = next_code[len as usize];
+            next_code[len as usize] += 1;
+            if len as u32 <= FAST_BITS {
+                // codes arrive most significant bit first, the lookup is by
+                // bits as they come out of the reader
+                let reversed = (code.reverse_bits() >> (32 - len as u32)) as usize;
+                for entry in (reversed..1 << FAST_BITS).step_by(1 << len) {
+                    fast[entry] = (symbol as u16, len);
+                }
+            }
+        }
+        Ok(Huffman { fast, counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, String> {
+        let (symbol, len) = self.fast[input.peek(FAST_BITS) as usize];
+        if len > 0 {
+            input.consume(len as u32);
+            return Ok(symbol);
+        }
+        // the long way, one bit at a time
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= input.bits(1) as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+// The code length codes come in this order, most likely first
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn dynamic_tables(input: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literals = input.bits(5) as usize + 257;
+    let distances = input.bits(5) as usize + 1;
+    let code_lengths = input.bits(4) as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err("too many Huffman codes".to_string());
+    }
+
+    let mut lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = input.bits(3) as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+
+    // literal and distance code lengths run together, repeats can cross over
+    let mut lengths = vec![0u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err("repeated code length with nothing before it".to_string());
+                }
+                (lengths[i - 1], 3 + input.bits(2) as usize)
+            }
+            17 => (0, 3 + input.bits(3) as usize),
+            _ => (0, 11 + input.bits(7) as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err("code lengths run past the end".to_string());
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err("no end of block code".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+}
+
+fn inflate_block(
+    input: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), String> {
+    loop {
+        if input.overrun() {
+            return Err("deflate data is cut short".to_string());
+        }
+        let symbol = literals.decode(input)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let l = symbol - 257;
+        if l >= LENGTH_BASE.len() {
+            return Err("invalid length code".to_string());
+        }
+        let length = LENGTH_BASE[l] as usize + input.bits(LENGTH_EXTRA[l] as u32) as usize;
+        let d = distances.decode(input)? as usize;
+        if d >= DIST_BASE.len() {
+            return Err("invalid distance code".to_string());
+        }
+        let distance = DIST_BASE[d] as usize + input.bits(DIST_EXTRA[d] as u32) as usize;
+        if distance > out.len() {
+            return Err("distance reaches back before the start".to_string());
+        }
+        // byte by byte, since the copy can overlap what it's writing
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+// Raw deflate data back to bytes. Also says how much of the input it used.
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize), String> {
+    let mut input = BitReader::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = input.bits(1) == 1;
+        match input.bits(2) {
+            0 => {
+                input.align();
+                let len = input.bits(16);
+                if input.bits(16) != !len & 0xffff {
+                    return Err("stored block length doesn't match its complement".to_string());
+                }
+                for _ in 0..len {
+                    out.push(input.bits(8) as u8);
+                }
+            }
+            1 => {
+                let (literals, distances) = fixed_tables();
+                inflate_block(&mut input, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_tables(&mut input)?;
+                inflate_block(&mut input, &mut out, &literals, &distances)?;
+            }
+            _ => return Err("invalid block type".to_string()),
+        }
+        if input.overrun() {
+            return Err("deflate data is cut short".to_string());
+        }
+        if last {
+            return Ok((out, input.position()));
+        }
+    }
+}
+
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 6 || data[0] & 0x0f != 8 || (data[0] as u16 * 256 + data[1] as u16) % 31 != 0 {
+        return Err("not a zlib stream".to_string());
+    }
+    if data[1] & 0x20 != 0 {
+        return Err("zlib preset dictionaries aren't supported".to_string());
+    }
+    let (out, used) = inflate(&data[2..])?;
+    let checksum = data.get(2 + used..6 + used).ok_or("zlib checksum is missing")?;
+    if u32::from_be_bytes(checksum.try_into().unwrap()) != adler32(&out) {
+        return Err("zlib checksum doesn't match".to_string());
+    }
+    Ok(out)
+}
+
+// Undo the per-row filters, in place. bpp is the bytes per pixel, at least 1.
+fn unfilter_rows(data: &mut [u8], stride: usize, height: usize, bpp: usize) -> Result<(), String> {
+    for y in 0..height {
+        let (before, rest) = data.split_at_mut(y * (stride + 1));
+        let above = if y == 0 { None } else { Some(&before[before.len() - stride..]) };
+        let filter = rest[0];
+        let row = &mut rest[1..stride + 1];
+        for i in 0..stride {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = above.map_or(0, |above| above[i]);
+            let c = if i >= bpp { above.map_or(0, |above| above[i - bpp]) } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(format!("unknown filter type {} on row {}", filter, y)),
+            };
+            row[i] = row[i].wrapping_add(predicted);
+        }
+    }
+    Ok(())
+}
+
+// Decode a PNG into RGB floats in 0..1, rows top to bottom. The values are
+// as stored, so usually still sRGB encoded. Alpha is dropped.
+pub fn read_png(bytes: &[u8]) -> Result<(u32, u32, Vec<f32>), String> {
+    if bytes.len() < 8 || bytes[..8] != SIGNATURE {
+        return Err("not a PNG file".to_string());
+    }
+    let mut header: Option<[u8; 13]> = None;
+    let mut palette: Vec<u8> = Vec::new();
+    let mut compressed = Vec::new();
+    let mut at = 8;
+    loop {
+        let chunk_header = bytes.get(at..at + 8).ok_or("file ends before IEND")?;
+        let len = u32::from_be_bytes(chunk_header[..4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = chunk_header[4..].try_into().unwrap();
+        let body = bytes.get(at + 4..at + 8 + len).ok_or("chunk runs past the end of the file")?;
+        let crc = bytes.get(at + 8 + len..at + 12 + len).ok_or("chunk runs past the end of the file")?;
+        if crc32(body) != u32::from_be_bytes(crc.try_into().unwrap()) {
+            return Err(format!("bad CRC on {} chunk", String::from_utf8_lossy(&kind)));
+        }
+        let data = &body[4..];
+        at += 12 + len;
+        match &kind {
+            b"IHDR" => header = Some(data.try_into().map_err(|_| "IHDR is the wrong size")?),
+            b"PLTE" => palette = data.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            // ancillary chunks (lowercase first letter) are safe to skip
+            _ if kind[0].is_ascii_lowercase() => (),
+            _ => return Err(format!("unknown critical chunk {}", String::from_utf8_lossy(&kind))),
+        }
+    }
+
+    let header = header.ok_or("no IHDR chunk")?;
+    let width = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let (depth, colour_type) = (header[8], header[9]);
+    if header[10] != 0 || header[11] != 0 {
+        return Err("unknown compression or filter method".to_string());
+    }
+    if header[12] != 0 {
+        return Err("interlaced PNGs aren't supported".to_string());
+    }
+    if width == 0 || height == 0 || (width as u64 * height as u64) > (1 << 28) {
+        return Err(format!("unusable image size {}x{}", width, height));
+    }
+    let channels = match (colour_type, depth) {
+        (0, 1 | 2 | 4 | 8 | 16) => 1,
+        (2, 8 | 16) => 3,
+        (3, 1 | 2 | 4 | 8) => 1,
+        (4, 8 | 16) => 2,
+        (6, 8 | 16) => 4,
+        _ => return Err(format!("invalid colour type {} with bit depth {}", colour_type, depth)),
+    };
+    if colour_type == 3 && (palette.is_empty() || palette.len() % 3 != 0) {
+        return Err("paletted image without a usable PLTE chunk".to_string());
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    let bits_per_pixel = channels * depth as usize;
+    let stride = (width * bits_per_pixel).div_ceil(8);
+    let mut data = zlib_decompress(&compressed)?;
+    if data.len() < (stride + 1) * height {
+        return Err("image data is cut short".to_string());
+    }
+    unfilter_rows(&mut data, stride, height, bits_per_pixel.div_ceil(8))?;
+
+    let max = ((1u32 << depth) - 1) as f32;
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        let row = &data[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        // channel c of pixel x, as an integer
+        let sample = |x: usize, c: usize| -> u32 {
+            let bit = (x * channels + c) * depth as usize;
+            match depth {
+                16 => u16::from_be_bytes([row[bit / 8], row[bit / 8 + 1]]) as u32,
+                8 => row[bit / 8] as u32,
+                // packed into bytes from the most significant end
+                _ => (row[bit / 8] >> (8 - depth as usize - bit % 8)) as u32 & ((1 << depth) - 1),
+            }
+        };
+        for x in 0..width {
+            let pixel = match colour_type {
+                0 | 4 => {
+                    let v = sample(x, 0) as f32 / max;
+                    [v, v, v]
+                }
+                3 => {
+                    let i = sample(x, 0) as usize * 3;
+                    let entry = palette.get(i..i + 3).ok_or("palette index out of range")?;
+                    [entry[0] as f32 / 255.0, entry[1] as f32 / 255.0, entry[2] as f32 / 255.0]
+                }
+                _ => [sample(x, 0) as f32 / max, sample(x, 1) as f32 / max, sample(x, 2) as f32 / max],
+            };
+            rgb.extend(pixel);
+        }
+    }
+    Ok((width as u32, height as u32, rgb))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[]), 1);
+    }
+
+    #[test]
+    fn deflate_matches_known_output() {
+        // literals only, checked against the spec by hand: "a" then end of block
+        assert_eq!(deflate(b"a"), vec![0x4b, 0x04, 0x00]);
+        // long runs should collapse into back-references
+        let zeros = vec![0u8; 10_000];
+        assert!(deflate(&zeros).len() < 100);
+        // past the window the match chains wrap around their buffer
+        let data: Vec<u8> = (0..100_000u64).map(|i| ((i * i % 251) ^ (i / 4096)) as u8).collect();
+        assert_eq!(zlib_decompress(&zlib_compress(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn png_layout() {
+        let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let mut bytes = Vec::new();
+        write_png(&mut bytes, 2, 2, &rgb).unwrap();
+        assert_eq!(bytes[..8], SIGNATURE);
+
+        // walk the chunks, checking lengths and CRCs along the way
+        let mut kinds = Vec::new();
+        let mut at = 8;
+        while at < bytes.len() {
+            let len = u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+            let body = &bytes[at + 4..at + 8 + len];
+            let crc = u32::from_be_bytes(bytes[at + 8 + len..at + 12 + len].try_into().unwrap());
+            assert_eq!(crc32(body), crc);
+            kinds.push(String::from_utf8(body[..4].to_vec()).unwrap());
+            at += 12 + len;
+        }
+        assert_eq!(at, bytes.len());
+        assert_eq!(kinds, ["IHDR", "IDAT", "IEND"]);
+        assert_eq!(bytes[16..24], [0, 0, 0, 2, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn inflate_known_streams() {
+        // zlib level 9 output, which uses a dynamic Huffman block
+        let compressed = [
+            0x78, 0xda, 0x85, 0xcb, 0xcb, 0x09, 0x80, 0x30, 0x10, 0x05, 0xc0, 0x56, 0x5e, 0x01, 0x22, 0xe4,
+            0x9f, 0x2d, 0xc7, 0xc0, 0x8a, 0x87, 0x60, 0x40, 0x03, 0xb6, 0x6f, 0x01, 0x59, 0x78, 0xe7, 0x61,
+            0x44, 0xd0, 0xc6, 0x9c, 0x5d, 0x5f, 0x8c, 0x13, 0x4d, 0xf5, 0xc1, 0xb8, 0x31, 0x2f, 0xc5, 0x77,
+            0xf4, 0xbe, 0x41, 0x16, 0xdf, 0x21, 0x95, 0x9c, 0x6a, 0x9c, 0x42, 0x4e, 0x31, 0x4e, 0x26, 0x27,
+            0x1b, 0x27, 0x91, 0x93, 0x8c, 0x13, 0xc9, 0x89, 0xc6, 0x09, 0xe4, 0x04, 0xe3, 0x78, 0x72, 0xbc,
+            0x71, 0x1c, 0x39, 0x6e, 0x3d, 0x3f, 0x9d, 0xe8, 0x9b, 0x65,
+        ];
+        let expected: String = (91..100).rev()
+            .map(|i| format!("{} bottles of beer on the wall, {} bottles of beer. ", i, i))
+            .collect();
+        assert_eq!(zlib_decompress(&compressed).unwrap(), expected.as_bytes());
+
+        // level 0: a single stored block
+        let stored = [
+            0x78, 0x01, 0x01, 0x1c, 0x00, 0xe3, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x20, 0x62, 0x6c,
+            0x6f, 0x63, 0x6b, 0x2c, 0x20, 0x6e, 0x6f, 0x20, 0x63, 0x6f, 0x6d, 0x70, 0x72, 0x65, 0x73, 0x73,
+            0x69, 0x6f, 0x6e, 0x99, 0x11, 0x0a, 0xb8,
+        ];
+        assert_eq!(zlib_decompress(&stored).unwrap(), b"stored block, no compression");
+
+        // and whatever our own compressor makes
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).collect();
+        assert_eq!(zlib_decompress(&zlib_compress(&data)).unwrap(), data);
+
+        // damage gets noticed
+        assert!(zlib_decompress(&compressed[..compressed.len() - 10]).is_err());
+        let mut flipped = compressed;
+        flipped[40] ^= 0x10;
+        assert!(zlib_decompress(&flipped).is_err());
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let rgb: Vec<u8> = (0..7 * 5 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let mut bytes = Vec::new();
+        write_png(&mut bytes, 7, 5, &rgb).unwrap();
+        let (width, height, decoded) = read_png(&bytes).unwrap();
+        assert_eq!((width, height), (7, 5));
+        let decoded: Vec<u8> = decoded.iter().map(|v| (v * 255.0).round() as u8).collect();
+        assert_eq!(decoded, rgb);
+    }
+
+    #[test]
+    fn png_palette_and_grey() {
+        let png = |colour_type: u8, depth: u8, extra: &[(&[u8; 4], Vec<u8>)], rows: &[u8]| {
+            let mut bytes = SIGNATURE.to_vec();
+            let mut header = vec![0, 0, 0, 3, 0, 0, 0, 1, depth, colour_type, 0, 0, 0];
+            header[3] = 3; // 3x1 pixels
+            write_chunk(&mut bytes, b"IHDR", &header).unwrap();
+            for (kind, data) in extra {
+                write_chunk(&mut bytes, kind, data).unwrap();
+            }
+            write_chunk(&mut bytes, b"IDAT", &zlib_compress(rows)).unwrap();
+            write_chunk(&mut bytes, b"IEND", &[]).unwrap();
+            read_png(&bytes)
+        };
+
+        // 2-bit palette indices 2, 0, 1 packed into one byte
+        let palette = vec![255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let (_, _, rgb) = png(3, 2, &[(b"PLTE", palette), (b"tEXt", b"a\0b".to_vec())], &[0, 0b1000_0100]).unwrap();
+        assert_eq!(rgb, [0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+
+        // 16-bit grey
+        let (_, _, rgb) = png(0, 16, &[], &[0, 0xff, 0xff, 0, 0, 0x80, 0x00]).unwrap();
+        assert_eq!(rgb[0], 1.0);
+        assert_eq!(rgb[3..6], [0.0; 3]);
+        assert!((rgb[6] - 0.5).abs() < 1e-4);
+
+        assert!(png(3, 2, &[], &[0, 0]).is_err()); // no palette
+        assert!(png(2, 4, &[], &[0, 0]).is_err()); // not a valid combination
+        assert!(png(0, 8, &[(b"ABCD", vec![])], &[0, 1, 2, 3]).is_err()); // unknown critical chunk
+    }
+}
@@ -9,6 +9,7 @@ use std::ops::{
     Div,
     DivAssign,
     Neg,
+    Index,
 };
 use std::fmt;
 use std::fmt::Display;
@@ -57,6 +58,14 @@ where T: std::ops::Add<Output = T>{
     }
 }
 
+impl <T> Sub for Vec2 <T>
+where T: std::ops::Sub<Output = T>{
+    type Output = Vec2<T>;
+    fn sub(self, other: Vec2<T>) -> Vec2<T> {
+        Vec2 { x: self.x - other.x, y: self.y - other.y }
+    }
+}
+
 impl <T> Mul for Vec2<T> 
 where T: std::ops::Mul<Output = T>{
     type Output = Vec2<T>;
@@ -143,32 +152,6 @@ impl Vec3{
         }
     }
 
-    pub fn rand_in_unit_sphere(srng: &mut SmallRng) -> Vec3 {
-        let distrib = Uniform::new(-1.0, 1.0);
-        loop {
-            let p = Vec3::rand(srng, distrib);
-            if p.length_squared() >= 1.0 { continue; }
-            else { return p; }
-        }
-    }
-
-    pub fn rand_in_unit_disk(srng: &mut SmallRng) -> Vec3 {
-        let distrib = Uniform::new(-1.0, 1.0);
-        loop {
-            let p = Vec3 {
-                x: srng.sample(distrib),
-                y: srng.sample(distrib),
-                z: 0.0,
-            };
-            if p.length_squared() >= 1.0 { continue; }
-            else { return p; }
-        }
-    }
-
-    pub fn rand_unit_vector(srng: &mut SmallRng) -> Vec3 {
-        return Vec3::as_unit(Vec3::rand_in_unit_sphere(srng));
-    }
-
 	pub fn length(&self) -> f32 {
 		self.length_squared().sqrt()
 	}
@@ -177,22 +160,6 @@ impl Vec3{
 		(self.x * self.x) + (self.y * self.y) + (self.z * self.z)
 	}
     
-    // roughly equivalent to the `void write_color(...)` in the book
-    pub fn print_ppm(&self, samples_per_pixel: u32) -> String {
-
-        let scale = 1.0 / samples_per_pixel as f32;
-        
-        // now with gamma correction
-        let r = (self.x * scale).sqrt();
-        let g = (self.y * scale).sqrt();
-        let b = (self.z * scale).sqrt();
-        
-        let ir = (r.clamp( 0.0, 0.999) * 256.0) as i32;
-        let ig = (g.clamp( 0.0, 0.999) * 256.0) as i32;
-        let ib = (b.clamp( 0.0, 0.999) * 256.0) as i32;
-        format!("{} {} {}", ir, ig, ib)
-    }
-    
     pub fn near_zero(&self) -> bool {
         let epsilon: f32 = 1e-4;
         return 
@@ -231,6 +198,24 @@ impl Vec3{
         v / len
     }
 
+    // component-wise minimum
+    pub fn min(a: Vec3, b: Vec3) -> Vec3 {
+        Vec3 {
+            x: a.x.min(b.x),
+            y: a.y.min(b.y),
+            z: a.z.min(b.z),
+        }
+    }
+
+    // component-wise maximum
+    pub fn max(a: Vec3, b: Vec3) -> Vec3 {
+        Vec3 {
+            x: a.x.max(b.x),
+            y: a.y.max(b.y),
+            z: a.z.max(b.z),
+        }
+    }
+
 }
 impl Add for Vec3 {
 	type Output = Vec3;
@@ -358,6 +343,19 @@ impl DivAssign<f32> for Vec3 {
 	}
 }
 
+// Axis access: 0 => x, 1 => y, 2 => z
+impl Index<usize> for Vec3 {
+    type Output = f32;
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 axis out of range: {}", axis),
+        }
+    }
+}
+
 impl Neg for Vec3{
     type Output = Self;
     fn neg(self) -> Self::Output {
@@ -382,6 +380,7 @@ impl Display for Vec3 {
 pub struct Ray{
     pub orig: Vec3,
     pub dir: Vec3,
+    pub time: f32, // when it was sent, for anything that moves
 }
 
 impl Ray{
@@ -390,7 +389,7 @@ impl Ray{
     }
 }
 
-#[derive (Copy, Clone)]
+#[derive (Copy, Clone, PartialEq, Debug)]
 pub struct Rect {
     pub x: i32,
     pub y: i32,
@@ -408,12 +407,108 @@ impl Rect{
 
     pub fn size(&self) -> Vec2i {
         Vec2i {
-            x: self.w - self.x,
-            y: self.h - self.y,
+            x: self.w,
+            y: self.h,
         }
     }
 }
 
+// Orthonormal basis built around w. Handy for turning samples generated
+// around +z into samples around a surface normal or a light direction.
+#[derive (Copy, Clone, Debug)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    pub fn from_w(n: Vec3) -> Onb {
+        let w = Vec3::as_unit(n);
+        let a = if w.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+        let v = Vec3::as_unit(Vec3::cross(w, a));
+        let u = Vec3::cross(w, v);
+        Onb { u, v, w }
+    }
+
+    // local (u, v, w) coordinates to world space
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        self.u * a.x + self.v * a.y + self.w * a.z
+    }
+}
+
+// Axis-aligned bounding box
+#[derive (Copy, Clone, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    // box spanning any two opposite corners
+    pub fn new(a: Vec3, b: Vec3) -> Aabb {
+        Aabb {
+            min: Vec3::min(a, b),
+            max: Vec3::max(a, b),
+        }
+    }
+
+    // A box that contains nothing. Merging anything into it yields that thing.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::ones() * f32::INFINITY,
+            max: Vec3::ones() * f32::NEG_INFINITY,
+        }
+    }
+
+    pub fn surrounding(a: Aabb, b: Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::min(a.min, b.min),
+            max: Vec3::max(a.max, b.max),
+        }
+    }
+
+    pub fn grow(&self, p: Vec3) -> Aabb {
+        Aabb {
+            min: Vec3::min(self.min, p),
+            max: Vec3::max(self.max, p),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.extent();
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0; // empty box
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    // Slab test. Written with min/max rather than branches so it stays tight
+    // inside BVH traversal. Boxes that are flat along an axis (axis-aligned
+    // triangles) still count as hit, and a ray running parallel to a slab
+    // produces NaN/inf terms that min/max quietly discard.
+    pub fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> bool {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for axis in 0..3 {
+            let inv_d = 1.0 / r.dir[axis];
+            let t0 = (self.min[axis] - r.orig[axis]) * inv_d;
+            let t1 = (self.max[axis] - r.orig[axis]) * inv_d;
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+        }
+        t_min <= t_max
+    }
+}
+
 #[cfg(test)]
 mod test{
     use super::*;
@@ -678,7 +773,8 @@ mod test{
     fn check_lerp(){
         let ray = Ray{
             orig: Vec3::new(0.0, 0.0, 0.0),
-            dir: Vec3::new(1.0, 1.0, 0.0)
+            dir: Vec3::new(1.0, 1.0, 0.0),
+            time: 0.0,
         };
         let half = ray.at(0.5);
         assert_eq!(
@@ -686,4 +782,77 @@ mod test{
             Vec3::new(0.5, 0.5, 0.0)
         );
     }
+
+    #[test]
+    fn aabb_hit_through_box(){
+        let bbox = Aabb::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::ones());
+        let ray = Ray{
+            orig: Vec3::new(-5.0, 0.5, 0.0),
+            dir: Vec3::new(1.0, 0.0, 0.0),
+            time: 0.0,
+        };
+        assert!(bbox.hit(ray, 0.0, f32::INFINITY));
+        assert!(!bbox.hit(ray, 0.0, 3.0)); // stops short of the box
+    }
+
+    #[test]
+    fn aabb_miss_beside_box(){
+        let bbox = Aabb::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::ones());
+        let ray = Ray{
+            orig: Vec3::new(-5.0, 2.0, 0.0),
+            dir: Vec3::new(1.0, 0.0, 0.0),
+            time: 0.0,
+        };
+        assert!(!bbox.hit(ray, 0.0, f32::INFINITY));
+    }
+
+    #[test]
+    fn aabb_surrounding(){
+        let a = Aabb::new(Vec3::zero(), Vec3::ones());
+        let b = Aabb::new(Vec3::new(-1.0, 0.5, 0.5), Vec3::new(0.0, 2.0, 0.5));
+        let c = Aabb::surrounding(a, b);
+        assert_eq!(c.min, Vec3::new(-1.0, 0.0, 0.0));
+        assert_eq!(c.max, Vec3::new(1.0, 2.0, 1.0));
+        assert_eq!(Aabb::surrounding(Aabb::empty(), a), a);
+    }
+
+    #[test]
+    fn aabb_hit_flat_box(){
+        // zero thickness along z, like an axis-aligned triangle would have
+        let bbox = Aabb::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 0.0));
+        let ray = Ray{
+            orig: Vec3::new(0.0, 0.0, -5.0),
+            dir: Vec3::new(0.0, 0.0, 1.0),
+            time: 0.0,
+        };
+        assert!(bbox.hit(ray, 0.0, f32::INFINITY));
+    }
+
+    #[test]
+    fn aabb_parallel_ray(){
+        let bbox = Aabb::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::ones());
+        let inside = Ray{
+            orig: Vec3::new(-5.0, 0.0, 0.0),
+            dir: Vec3::new(1.0, 0.0, 0.0),
+            time: 0.0,
+        };
+        let outside = Ray{
+            orig: Vec3::new(-5.0, 0.0, 3.0),
+            dir: Vec3::new(1.0, 0.0, 0.0),
+            time: 0.0,
+        };
+        assert!(bbox.hit(inside, 0.0, f32::INFINITY));
+        assert!(!bbox.hit(outside, 0.0, f32::INFINITY));
+    }
+
+    #[test]
+    fn onb_is_orthonormal(){
+        let onb = Onb::from_w(Vec3::new(1.0, 2.0, 3.0));
+        assert!((onb.u.length() - 1.0).abs() < 1e-6);
+        assert!((onb.v.length() - 1.0).abs() < 1e-6);
+        assert!(Vec3::dot(onb.u, onb.v).abs() < 1e-6);
+        assert!(Vec3::dot(onb.v, onb.w).abs() < 1e-6);
+        assert!(Vec3::dot(onb.w, onb.u).abs() < 1e-6);
+        assert!((onb.local(Vec3::new(0.0, 0.0, 1.0)) - onb.w).near_zero());
+    }
 }
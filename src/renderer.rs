@@ -7,132 +7,444 @@ use crate::primitives::{
     Rect,
 };
 use crate::scene::{
-    Hittable,
+    HitRecord,
+    Medium,
     Scene,
 };
-
-use rand::rngs::SmallRng;
+use crate::filter::Filter;
+use crate::sampler::{Sampler, SamplerKind};
+use crate::tonemap::luminance;
 
 use itertools::{self, Itertools};
 
-const SKY_COLOR: Vec3 = Vec3 { x: 0.5, y: 0.7, z: 1.0};
+use std::ops::Range;
 
 pub struct RenderProperties {
     pub samples: u32, // samples are averaged results over a pixel
     pub bounces: u32, // bounces are how far the ray will travel (in hits not total distance)
+    pub roulette_depth: u32, // bounces before Russian roulette can end a path early
+    pub seed: u64,    // base seed that every per-pixel random stream is derived from
+    pub adaptive: Option<AdaptiveProperties>, // None takes every pixel to `samples`
+    pub sampler: SamplerKind, // how the random numbers for each sample are picked
+    pub filter: Filter, // how samples are shared out between nearby pixels
 }
 
-fn to_uv(coord: Vec2i, img_size: Vec2i) -> Vec2f {
-    let u = (coord.x as f32) / ((img_size.x - 1) as f32);
-    let v = (coord.y as f32) / ((img_size.y - 1) as f32);
-    Vec2f::new(u, v)
+// Adaptive sampling. Each pixel gets at least min_samples, then keeps going
+// (up to RenderProperties::samples) until the estimated relative error of its
+// brightness drops below threshold.
+#[derive (Copy, Clone, Debug)]
+pub struct AdaptiveProperties {
+    pub min_samples: u32,
+    pub threshold: f32,
 }
 
-fn ray_color(
-    r: Ray, surface: &Hittable, depth: u32,
-    rng: &mut SmallRng,
+// From a spot on the film, in pixels from the bottom left corner of the
+// image, to the camera's 0..1 coordinates. Pixel (x, y) covers x..x+1 and
+// y..y+1.
+fn to_uv(pos: Vec2f, img_size: Vec2i) -> Vec2f {
+    Vec2f::new(pos.x / img_size.x as f32, pos.y / img_size.y as f32)
+}
+
+// Power heuristic (beta = 2) weight for a sample drawn with density pdf,
+// when another strategy could have made the same sample with other_pdf
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+// Most media boundaries a single ray segment is allowed to pass through.
+// Only matters for broken (unclosed or overlapping) boundaries, which could
+// otherwise keep a ray bouncing between them forever.
+const MAX_CROSSINGS: u32 = 64;
+
+// The first real surface along r, stepping through the boundaries of any
+// media on the way, along with the fraction of light that makes it through
+// them. medium is what r starts out in.
+fn trace_shadow<'a>(
+    scene: &'a Scene, r: Ray, mut medium: Option<&'a Medium>,
+) -> (Option<HitRecord<'a>>, f32) {
+    let mut ray = r;
+    let mut transmittance = 1.0;
+    for _ in 0..MAX_CROSSINGS {
+        let hit = scene.world.hit(ray, 0.001, f32::INFINITY);
+        if let Some(m) = medium {
+            let t = hit.as_ref().map_or(f32::INFINITY, |rec| rec.t);
+            transmittance *= m.transmittance(t * ray.dir.length());
+            if transmittance <= 0.0 {
+                return (None, 0.0);
+            }
+        }
+        match hit {
+            Some(rec) if rec.medium.is_some() => {
+                medium = if rec.front_face { rec.medium } else { None };
+                ray = Ray { orig: rec.p, ..ray };
+            }
+            hit => return (hit, transmittance),
+        }
+    }
+    (None, 0.0)
+}
+
+// Light arriving at a diffuse hit (or a scattering point in a medium)
+// straight from one of the scene's lights, weighted against the chance of
+// the BSDF sample finding the same light. time is when the ray that made the
+// hit was sent, and medium is what the hit sits in.
+fn sample_direct(
+    record: &HitRecord, scene: &Scene, time: f32, medium: Option<&Medium>,
+    sampler: &mut Sampler,
 ) -> Vec3 {
-    // recursion guard
-    if depth == 0 {
-        return Vec3::zero();
+    let light_dir = match scene.sample_light(record.p, sampler) {
+        Some(dir) => dir,
+        None => return Vec3::zero(),
+    };
+    let (bsdf_cos, bsdf_pdf) = match record.material.eval(record, light_dir) {
+        Some((f, pdf)) if pdf > 0.0 => (f, pdf),
+        _ => return Vec3::zero(), // light is behind the surface
+    };
+    // shadow ray: whatever it runs into first has to be a light, and any
+    // media along the way dim it
+    let shadow = Ray { orig: record.p, dir: light_dir, time };
+    match trace_shadow(scene, shadow, medium) {
+        (Some(light_hit), transmittance) if light_hit.material.is_emissive() => {
+            let light_pdf = scene.light_pdf(record.p, light_dir);
+            if light_pdf <= 0.0 {
+                return Vec3::zero();
+            }
+            let weight = power_heuristic(light_pdf, bsdf_pdf);
+            bsdf_cos * light_hit.material.emitted(&light_hit) * (transmittance * weight / light_pdf)
+        }
+        _ => Vec3::zero(),
     }
-    
-    // cast a ray, interrogate hit record
-    if let Some(record) = surface.hit(r, 0.001, f32::INFINITY){
+}
+
+// Light arriving back along r, following it for at most max_depth hits.
+//
+// throughput is how much of what's found further along still makes it back
+// to the camera. bsdf_pdf is the density the last bounce picked the ray with,
+// when that bounce also sampled the lights directly. Any light the ray lands
+// on is then weighted against the light sample. Camera rays and rays leaving
+// mirrors/glass have None and count emitters at full strength.
+//
+// Inside a medium, a free-flight distance is drawn each step. If it falls
+// short of the next surface the ray scatters there instead, which counts as
+// a bounce. Crossing a medium's boundary doesn't.
+fn ray_color(
+    r: Ray, scene: &Scene, max_depth: u32, roulette_depth: u32,
+    sampler: &mut Sampler,
+) -> Vec3 {
+    let mut color = Vec3::zero();
+    let mut throughput = Vec3::ones();
+    let mut ray = r;
+    let mut bsdf_pdf: Option<f32> = None;
+    // The camera is assumed to start out in plain air
+    let mut medium: Option<&Medium> = None;
+    // where the ray last scattered, which stays put across media boundaries
+    let mut origin = r.orig;
+    let mut depth = 0;
+    let mut crossings = 0;
+
+    while depth < max_depth {
+        // cast a ray, interrogate hit record
+        let hit = scene.world.hit(ray, 0.001, f32::INFINITY);
+        let hit = match medium {
+            Some(m) => {
+                let t = m.free_flight(ray, sampler.get_1d());
+                if hit.as_ref().is_none_or(|rec| t < rec.t) {
+                    Some(m.scatter_record(ray, t))
+                } else {
+                    hit
+                }
+            }
+            None => hit,
+        };
+        let record = match hit {
+            Some(record) => record,
+            // when nothing is struck, the background is all there is
+            None => {
+                color += throughput * scene.background.color(ray);
+                break;
+            }
+        };
+
+        // going into or out of a medium, carry on in the same direction
+        if let Some(m) = record.medium {
+            crossings += 1;
+            if crossings > MAX_CROSSINGS {
+                break;
+            }
+            medium = if record.front_face { Some(m) } else { None };
+            ray = Ray { orig: record.p, ..ray };
+            continue;
+        }
+        crossings = 0;
+
+        let mut emitted = record.material.emitted(&record);
+        if let Some(pdf) = bsdf_pdf {
+            if record.material.is_emissive() {
+                emitted *= power_heuristic(pdf, scene.light_pdf(origin, ray.dir));
+            }
+        }
+        color += throughput * emitted;
+
         let mut scattered = Ray {
             orig: Vec3::zero(),
             dir: Vec3::zero(),
+            time: ray.time,
         };
         let mut attenuation = Vec3::zero();
-        if record.material.scatter(
-            r,
+        if !record.material.scatter(
+            ray,
             &record,
             &mut attenuation,
             &mut scattered,
-            rng
+            sampler
         ) {
-            return attenuation * ray_color(
-                scattered, surface, depth-1, rng
-            );
+            break;
+        }
+
+        if record.material.is_specular() {
+            bsdf_pdf = None;
+        } else {
+            color += throughput * sample_direct(&record, scene, ray.time, medium, sampler);
+            bsdf_pdf = record.material.eval(&record, scattered.dir).map(|(_, pdf)| pdf);
+        }
+        throughput *= attenuation;
+        ray = scattered;
+        origin = ray.orig;
+        depth += 1;
+
+        // Russian roulette: past the first few bounces, end dim paths at
+        // random. The ones that survive are boosted by exactly the odds
+        // they beat, so on average nothing is lost.
+        if depth >= roulette_depth {
+            let survive = throughput.x.max(throughput.y).max(throughput.z).min(1.0);
+            if sampler.get_1d() >= survive {
+                break;
+            }
+            throughput /= survive;
         }
-    } // TODO: explicit else block
-    // Rust gets angry about the inner if{} block because it evaluates to ()
-    // when the else path is taken. This is a problem for a function
-    // that returns Vec3 and not ().
-
-    { // when nothing is struck, return sky color
-        let unitdir = Vec3::as_unit(r.dir);
-        let t = 0.5 * (unitdir.y + 1.0);
-        return Vec3::ones() * (1.0 - t) + SKY_COLOR * t
     }
+    color
 }
 
-fn sample_pixel(
+// One sample of a pixel: where on the film it landed, and the light it
+// brought back. Sample n is the same whichever pass or range draws it, so a
+// pixel can be built up over several calls.
+fn trace_sample(
     coord: Vec2i, // location in image/screen space
     scene: &Scene,  // scene we're drawing
     render_props: &RenderProperties,
     img_size: Vec2i,
-    // Supplied by the execution environment (the thread)
-    rng: &mut SmallRng,
-) -> Vec3{
-    (0..render_props.samples)
-    .fold(
-        Vec3::zero(),
-        |color, _sample| -> Vec3 {
-            let uv = to_uv(coord, img_size);
-            let ray = scene.camera.get_ray(uv.x, uv.y, rng);
-            if ray.dir.x.is_nan() {
-                panic!("Ray dir.x is NAN");
-            }
-            color + ray_color(ray, &scene.world, render_props.bounces, rng)
+    sample: u32,
+) -> (Vec2f, Vec3) {
+    let mut sampler = Sampler::new(
+        render_props.sampler,
+        render_props.seed,
+        coord,
+        sample,
+        render_props.samples,
+    );
+    // somewhere inside the pixel, not always its corner
+    let jitter = sampler.get_pixel_2d();
+    let pos = Vec2f::new(coord.x as f32 + jitter.x, coord.y as f32 + jitter.y);
+    let uv = to_uv(pos, img_size);
+    let ray = scene.camera.get_ray(uv.x, uv.y, &mut sampler);
+    if ray.dir.x.is_nan() {
+        panic!("Ray dir.x is NAN");
+    }
+    let color = ray_color(ray, scene, render_props.bounces, render_props.roulette_depth, &mut sampler);
+    (pos, color)
+}
+
+// Standard error of a pixel's mean brightness, relative to that brightness.
+// Dark pixels are measured against a floor so they can settle at all.
+fn relative_error(mean: f32, m2: f32, n: u32) -> f32 {
+    let variance = m2 / (n - 1) as f32;
+    (variance / n as f32).sqrt() / mean.max(0.01)
+}
+
+// Keep sampling one pixel until it looks converged, splatting the samples
+// into the tile as they come
+fn sample_pixel_adaptive(
+    coord: Vec2i,
+    scene: &Scene,
+    render_props: &RenderProperties,
+    img_size: Vec2i,
+    adaptive: &AdaptiveProperties,
+    tile: &mut Tile,
+) {
+    let min_samples = adaptive.min_samples.clamp(2, render_props.samples.max(2));
+    // running mean and squared deviation of luminance (Welford's method)
+    let mut mean = 0.0;
+    let mut m2 = 0.0;
+    let mut n = 0;
+    while n < render_props.samples.max(min_samples) {
+        let (pos, color) = trace_sample(coord, scene, render_props, img_size, n);
+        tile.splat(pos, color, &render_props.filter);
+        n += 1;
+        let l = luminance(color);
+        let delta = l - mean;
+        mean += delta / n as f32;
+        m2 += delta * (l - mean);
+        if n >= min_samples && relative_error(mean, m2, n) < adaptive.threshold {
+            break;
         }
-    )
+    }
+    tile.samples += n as u64;
 }
 
 pub struct Tile {
-    _bounds: Rect,
-    pub pixels: Vec<Vec3>,
+    pub bounds: Rect, // the pixels traced, plus however far the filter reaches past them
+    pub pixels: Vec<Vec3>, // weighted sums of the samples
+    pub weights: Vec<f32>, // what each pixel's sum has to be divided by
+    pub samples: u64, // how many samples were traced
 }
 
 impl Tile {
+    // Empty tile for the samples of the pixels in bounds to land in
+    fn for_pixels(bounds: Rect, filter: &Filter) -> Self {
+        let margin = filter.margin();
+        let bounds = Rect {
+            x: bounds.x - margin,
+            y: bounds.y - margin,
+            w: bounds.w + 2 * margin,
+            h: bounds.h + 2 * margin,
+        };
+        Self {
+            bounds,
+            pixels: vec![Vec3::zero(); (bounds.w * bounds.h) as usize],
+            weights: vec![0.0; (bounds.w * bounds.h) as usize],
+            samples: 0,
+        }
+    }
+
+    // Share one sample out between the pixels the filter reaches from where
+    // it landed
+    fn splat(&mut self, pos: Vec2f, color: Vec3, filter: &Filter) {
+        let r = filter.radius;
+        // pixel centres are at +0.5
+        let (x0, x1) = ((pos.x - 0.5 - r).ceil() as i32, (pos.x - 0.5 + r).floor() as i32);
+        let (y0, y1) = ((pos.y - 0.5 - r).ceil() as i32, (pos.y - 0.5 + r).floor() as i32);
+        let b = self.bounds;
+        for y in y0.max(b.y)..=y1.min(b.y + b.h - 1) {
+            for x in x0.max(b.x)..=x1.min(b.x + b.w - 1) {
+                let offset = Vec2f::new(pos.x - (x as f32 + 0.5), pos.y - (y as f32 + 0.5));
+                let weight = filter.evaluate(offset);
+                if weight != 0.0 {
+                    let index = ((y - b.y) * b.w + (x - b.x)) as usize;
+                    self.pixels[index] += color * weight;
+                    self.weights[index] += weight;
+                }
+            }
+        }
+    }
+
     pub fn render_tile(
         bounds: Rect,       // bounds of the region to render
         img_size: Vec2i,    // final image resolution (needed for proper UV mapping)
         scene: &Scene,
         properties: &RenderProperties, // TODO: Place image size in render properties?
-        rng: &mut SmallRng,
     ) -> Self {
-        let pixel_iter = (bounds.y..(bounds.y + bounds.h))
-            .cartesian_product( bounds.x..(bounds.x + bounds.w));
-        let pixels = pixel_iter.map(
-            |coord| -> Vec3 {
-                sample_pixel(
-                    Vec2i{x: coord.1, y: coord.0},
-                    scene,
-                    properties,
-                    img_size,
-                    rng,
-                )
-            }
-        ).collect();
-        Self {
-            _bounds: bounds,
-            pixels
+        let Some(adaptive) = properties.adaptive else {
+            return Self::render_samples(bounds, img_size, scene, properties, 0..properties.samples);
+        };
+        let mut tile = Self::for_pixels(bounds, &properties.filter);
+        for (y, x) in (bounds.y..(bounds.y + bounds.h)).cartesian_product(bounds.x..(bounds.x + bounds.w)) {
+            sample_pixel_adaptive(Vec2i { x, y }, scene, properties, img_size, &adaptive, &mut tile);
         }
+        tile
     }
-    pub fn render_line(
-        y: i32, // bounding rect and line
+
+    // Render only some of the samples for each pixel, e.g. one pass of a
+    // progressive render. The pixels hold the weighted sum, not the average.
+    pub fn render_samples(
+        bounds: Rect,
         img_size: Vec2i,
         scene: &Scene,
         properties: &RenderProperties,
-        rng: &mut SmallRng, // rng utils
+        samples: Range<u32>,
     ) -> Self {
-        Tile::render_tile(
-            Rect{ x: 0, y, w: img_size.x, h: 1 },
-            img_size,
-            scene,
-            properties,
-            rng
-        )
+        let mut tile = Self::for_pixels(bounds, &properties.filter);
+        let pixel_iter = (bounds.y..(bounds.y + bounds.h))
+            .cartesian_product( bounds.x..(bounds.x + bounds.w));
+        for (y, x) in pixel_iter {
+            for sample in samples.clone() {
+                let (pos, color) = trace_sample(Vec2i { x, y }, scene, properties, img_size, sample);
+                tile.splat(pos, color, &properties.filter);
+            }
+        }
+        tile.samples = samples.len() as u64 * (bounds.w * bounds.h) as u64;
+        tile
+    }
+}
+
+// Most pixels an image can have: 16384 x 16384. Much past that and the
+// framebuffer alone runs to gigabytes.
+pub const MAX_PIXELS: i32 = 1 << 28;
+
+// Whether a w x h image is something we can allocate
+pub fn image_size_ok(w: i32, h: i32) -> bool {
+    w > 0 && h > 0 && w.checked_mul(h).is_some_and(|n| n <= MAX_PIXELS)
+}
+
+// Pixel storage for the whole image, or some rectangle of it. Rows are
+// stored bottom-to-top, matching the UV space used by the camera, so row 0
+// is the bottom of the picture.
+pub struct Framebuffer {
+    pub size: Vec2i,
+    pub origin: Vec2i, // where pixel (0, 0) sits in the full image
+    pub pixels: Vec<Vec3>, // weighted sums of the samples
+    pub weights: Vec<f32>, // and how much weight went into each sum
+    pub samples: u64, // samples traced into it (counting the filter's margin)
+}
+
+impl Framebuffer {
+    pub fn new(size: Vec2i) -> Self {
+        Self::for_region(Rect { x: 0, y: 0, w: size.x, h: size.y })
+    }
+
+    pub fn for_region(region: Rect) -> Self {
+        assert!(image_size_ok(region.w, region.h), "Framebuffer of {}x{} is too big", region.w, region.h);
+        Self {
+            size: region.size(),
+            origin: region.pos(),
+            pixels: vec![Vec3::zero(); (region.w * region.h) as usize],
+            weights: vec![0.0; (region.w * region.h) as usize],
+            samples: 0,
+        }
+    }
+
+    // Add a tile's samples onto what's already there. Whatever the filter
+    // spread past the edge of the framebuffer is dropped.
+    pub fn accumulate(&mut self, tile: &Tile) {
+        let pos = tile.bounds.pos() - self.origin;
+        let size = tile.bounds.size();
+        for (i, (&sample, &weight)) in tile.pixels.iter().zip(&tile.weights).enumerate() {
+            let (x, y) = (pos.x + i as i32 % size.x, pos.y + i as i32 / size.x);
+            if x < 0 || y < 0 || x >= self.size.x || y >= self.size.y {
+                continue;
+            }
+            let index = (y * self.size.x + x) as usize;
+            self.pixels[index] += sample;
+            self.weights[index] += weight;
+        }
+        self.samples += tile.samples;
+    }
+
+    // Final value of one pixel, by its index in storage order. Filters with
+    // negative lobes can ring below zero next to bright edges, but there's no
+    // such thing as negative light, so that's cut off.
+    pub fn average(&self, index: usize) -> Vec3 {
+        let weight = self.weights[index];
+        if weight > 0.0 { Vec3::max(self.pixels[index] / weight, Vec3::zero()) } else { Vec3::zero() }
+    }
+
+    // Final pixel values, one row at a time in display order (top of the
+    // image first)
+    pub fn rows_top_down(&self) -> impl Iterator<Item = impl Iterator<Item = Vec3> + '_> {
+        let width = self.size.x as usize;
+        (0..self.size.y as usize).rev()
+            .map(move |row| (row * width..(row + 1) * width).map(|i| self.average(i)))
     }
 }
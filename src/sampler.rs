@@ -0,0 +1,392 @@
+
+// Where the random numbers for a path come from
+//
+// Every sample of a pixel asks for its numbers in the same order (lens,
+// then for each bounce the BSDF and the light), and each request gets the
+// next "dimension". Plain random numbers clump, so the error only falls off
+// as 1/sqrt(N). The other samplers spread the N samples of a pixel out over
+// each dimension, which gets rid of a lot of that noise for free.
+//
+// Nothing here keeps state between samples. A value depends only on the
+// seed, the pixel, the sample index and the dimension, so renders still come
+// out the same however the work is split up.
+
+use crate::primitives::{Vec2f, Vec2i, Vec3};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+use serde::Deserialize;
+
+#[derive (Copy, Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerKind {
+    // fresh random numbers every time, the way it's always been done here
+    #[default]
+    Independent,
+    // one sample per stratum, jittered inside it, strata shuffled per dimension.
+    // The strata are laid out for the pixel's total sample count, so an
+    // open-ended render can't use it, and a render resumed with a different
+    // count mixes samples from two layouts (still correct, just noisier).
+    Stratified,
+    // radical inverses in a different prime base per dimension
+    Halton,
+    // Sobol (0,2)-sequence with hash based Owen scrambling, after Burley 2020
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name.to_ascii_lowercase().as_str() {
+            "independent" | "random" => Some(SamplerKind::Independent),
+            "stratified" | "jittered" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None,
+        }
+    }
+}
+
+// SplitMix64 finalizer. Good enough avalanche that neighbouring pixels and
+// samples end up with unrelated seeds.
+pub fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn pixel_seed(seed: u64, coord: Vec2i) -> u64 {
+    let pixel = ((coord.x as u32 as u64) << 32) | (coord.y as u32 as u64);
+    mix64(seed ^ mix64(pixel))
+}
+
+// Random stream for one sample of one pixel. Depends only on its inputs, so
+// the image comes out the same no matter which thread or tile draws the pixel.
+pub fn pixel_rng(seed: u64, coord: Vec2i, sample: u32) -> SmallRng {
+    SmallRng::seed_from_u64(mix64(pixel_seed(seed, coord) ^ sample as u64))
+}
+
+// Largest f32 below 1. Samples are kept under it so [0, 1) stays half open.
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+// The first dimensions belong to the position inside the pixel
+const PIXEL_DIMENSIONS: u32 = 2;
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+// Kensler's hash based permutation of 0..n ("Correlated Multi-Jittered
+// Sampling", 2013). Picks out element i of a shuffle chosen by seed, without
+// ever building the shuffle.
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        // cycle walk until we land back inside 0..n
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % n
+}
+
+fn radical_inverse(base: u32, mut i: u32) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv = inv_base;
+    let mut result = 0.0;
+    while i > 0 {
+        result += (i % base) as f64 * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    result as f32
+}
+
+// Laine and Karras' hash, which only lets each bit affect the bits above it
+fn laine_karras(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+// Owen scrambling: each bit gets flipped depending on the bits before it,
+// which shuffles the points around without breaking up their strata
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Second dimension of the Sobol sequence (the first is just the index with
+// its bits reversed)
+fn sobol_second(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+fn to_unit(bits: u32) -> f32 {
+    // top 24 bits are all an f32 can hold, and keeps the result below 1
+    (bits >> 8) as f32 / (1 << 24) as f32
+}
+
+// Numbers for one sample of one pixel
+pub struct Sampler {
+    kind: SamplerKind,
+    seed: u64,    // already mixed with the pixel
+    index: u32,   // which of the pixel's samples this is
+    samples: u32, // how many the pixel is going to get, for stratifying
+    dimension: u32,
+    rng: SmallRng, // independent numbers, and anything past what a sequence covers
+}
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, seed: u64, coord: Vec2i, index: u32, samples: u32) -> Sampler {
+        Sampler {
+            kind,
+            seed: pixel_seed(seed, coord),
+            index,
+            samples: samples.max(1),
+            dimension: PIXEL_DIMENSIONS,
+            rng: pixel_rng(seed, coord, index),
+        }
+    }
+
+    // Seeds the shuffling and scrambling of one dimension
+    fn dimension_hash(&self, dimension: u32) -> u64 {
+        mix64(self.seed ^ mix64(dimension as u64 + 1))
+    }
+
+    fn take_dimensions(&mut self, n: u32) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += n;
+        dimension
+    }
+
+    pub fn get_1d(&mut self) -> f32 {
+        let dimension = self.take_dimensions(1);
+        self.sample_1d(dimension)
+    }
+
+    pub fn get_2d(&mut self) -> Vec2f {
+        let dimension = self.take_dimensions(2);
+        self.sample_2d(dimension)
+    }
+
+    // Where in the pixel this sample lands, 0..1 on both axes
+    pub fn get_pixel_2d(&mut self) -> Vec2f {
+        self.sample_2d(0)
+    }
+
+    fn sample_1d(&mut self, dimension: u32) -> f32 {
+        let hash = self.dimension_hash(dimension);
+        let v = match self.kind {
+            SamplerKind::Independent => self.rng.gen(),
+            SamplerKind::Stratified => {
+                let stratum = permute(self.index % self.samples, self.samples, hash as u32);
+                (stratum as f32 + self.rng.gen::<f32>()) / self.samples as f32
+            }
+            SamplerKind::Halton => match PRIMES.get(dimension as usize) {
+                // a random shift per pixel and dimension, wrapped around,
+                // keeps neighbouring pixels from all using the same points
+                Some(&base) => (radical_inverse(base, self.index) + to_unit(hash as u32)).fract(),
+                None => self.rng.gen(),
+            },
+            SamplerKind::Sobol => {
+                let index = nested_uniform_scramble(self.index, hash as u32);
+                to_unit(nested_uniform_scramble(index.reverse_bits(), (hash >> 32) as u32))
+            }
+        };
+        v.min(ONE_MINUS_EPSILON)
+    }
+
+    fn sample_2d(&mut self, dimension: u32) -> Vec2f {
+        let hash = self.dimension_hash(dimension);
+        let (x, y) = match self.kind {
+            SamplerKind::Independent => (self.rng.gen(), self.rng.gen()),
+            SamplerKind::Stratified => {
+                // the biggest square grid that fits in the sample count. Any
+                // samples past it start going round the grid again.
+                let side = ((self.samples as f64).sqrt() as u32).max(1);
+                let cell = permute(self.index % (side * side), side * side, hash as u32);
+                let jitter: (f32, f32) = (self.rng.gen(), self.rng.gen());
+                (
+                    ((cell % side) as f32 + jitter.0) / side as f32,
+                    ((cell / side) as f32 + jitter.1) / side as f32,
+                )
+            }
+            SamplerKind::Halton => (self.sample_1d(dimension), self.sample_1d(dimension + 1)),
+            SamplerKind::Sobol => {
+                // shuffling the index decorrelates pixels and dimensions, and
+                // keeps every power of two prefix well spread
+                let index = nested_uniform_scramble(self.index, hash as u32);
+                let x = nested_uniform_scramble(index.reverse_bits(), (hash >> 32) as u32);
+                let y = nested_uniform_scramble(sobol_second(index), mix64(hash) as u32);
+                (to_unit(x), to_unit(y))
+            }
+        };
+        Vec2f::new(x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+    }
+}
+
+// Turning uniform samples into the shapes the renderer wants
+
+// Uniform over the surface of the unit sphere
+pub fn unit_vector(u: Vec2f) -> Vec3 {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u.y;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Uniform inside the unit sphere. Needs a third number for the radius.
+pub fn in_unit_sphere(u: Vec2f, r: f32) -> Vec3 {
+    unit_vector(u) * r.cbrt()
+}
+
+// Uniform inside the unit disk (z = 0), using Shirley and Chiu's concentric
+// mapping so that nearby samples stay nearby
+pub fn in_unit_disk(u: Vec2f) -> Vec3 {
+    let a = 2.0 * u.x - 1.0;
+    let b = 2.0 * u.y - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec3::zero();
+    }
+    let quarter_pi = std::f32::consts::FRAC_PI_4;
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, quarter_pi * (b / a))
+    } else {
+        (b, 2.0 * quarter_pi - quarter_pi * (a / b))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ];
+
+    // The samples of one pixel for the first 2D dimension after the pixel's own
+    fn points(kind: SamplerKind, samples: u32) -> Vec<Vec2f> {
+        (0..samples)
+            .map(|i| Sampler::new(kind, 3, Vec2i { x: 5, y: 9 }, i, samples).get_2d())
+            .collect()
+    }
+
+    #[test]
+    fn samples_in_range_and_repeatable() {
+        for kind in KINDS {
+            for i in 0..64 {
+                let mut sampler = Sampler::new(kind, 1, Vec2i { x: 2, y: 3 }, i, 64);
+                let mut again = Sampler::new(kind, 1, Vec2i { x: 2, y: 3 }, i, 64);
+                // run well past the Halton primes
+                for _ in 0..100 {
+                    let (a, b) = (sampler.get_1d(), sampler.get_2d());
+                    assert!((0.0..1.0).contains(&a), "{:?} gave {}", kind, a);
+                    assert!((0.0..1.0).contains(&b.x) && (0.0..1.0).contains(&b.y), "{:?} gave {:?}", kind, b);
+                    assert_eq!((a, b), (again.get_1d(), again.get_2d()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn one_point_per_cell() {
+        // 16 samples on a 4x4 grid: stratified does it by construction, and
+        // Sobol (scrambled or not) because it's a (0,2)-sequence
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+            let mut cells = [0; 16];
+            for p in points(kind, 16) {
+                cells[(p.y * 4.0) as usize * 4 + (p.x * 4.0) as usize] += 1;
+            }
+            assert_eq!(cells, [1; 16], "{:?}", kind);
+        }
+        // Halton's first dimension is base 2, which puts 8 samples in 8
+        // different eighths
+        let mut bins = [0; 8];
+        for i in 0..8 {
+            let x = Sampler::new(SamplerKind::Halton, 3, Vec2i { x: 0, y: 0 }, i, 8).get_pixel_2d().x;
+            bins[(x * 8.0) as usize] += 1;
+        }
+        assert_eq!(bins, [1; 8]);
+    }
+
+    #[test]
+    fn sequences_beat_random() {
+        // integrate x*y over the unit square (exactly 1/4), averaged over
+        // a few pixels so one lucky pixel can't decide it
+        let error = |kind| -> f32 {
+            (0..8).map(|pixel| {
+                let estimate: f32 = (0..256)
+                    .map(|i| {
+                        let p = Sampler::new(kind, 11, Vec2i { x: pixel, y: 0 }, i, 256).get_2d();
+                        p.x * p.y
+                    })
+                    .sum::<f32>() / 256.0;
+                (estimate - 0.25).abs()
+            }).sum::<f32>() / 8.0
+        };
+        let random = error(SamplerKind::Independent);
+        for kind in [SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol] {
+            assert!(error(kind) < random * 0.5, "{:?}: {} vs {}", kind, error(kind), random);
+        }
+    }
+
+    #[test]
+    fn permute_is_a_permutation() {
+        for n in [1, 2, 7, 16, 100] {
+            let mut seen: Vec<u32> = (0..n).map(|i| permute(i, n, 0xdead_beef)).collect();
+            seen.sort();
+            assert_eq!(seen, (0..n).collect::<Vec<u32>>());
+        }
+    }
+
+    #[test]
+    fn warps_stay_inside() {
+        for p in points(SamplerKind::Sobol, 64) {
+            assert!((unit_vector(p).length() - 1.0).abs() < 1e-5);
+            assert!(in_unit_sphere(p, 0.999).length() <= 1.0);
+            assert!(in_unit_disk(p).length() <= 1.0 + 1e-6);
+        }
+    }
+}
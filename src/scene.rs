@@ -1,33 +1,178 @@
 
-use crate::primitives::{Vec3, Ray};
+use crate::primitives::{Vec2f, Vec3, Ray, Aabb, Onb};
+use crate::mesh::TriangleMesh;
+use crate::texture::Texture;
+
+use std::sync::Arc;
+
+use crate::sampler::{self, Sampler};
 
 use rand::Rng;
 use rand::rngs::SmallRng;
 use rand::distributions::Uniform;
 
-pub struct HitRecord{
+pub struct HitRecord<'a> {
     pub p: Vec3,
     pub normal: Vec3,
-    pub material: Material,
+    pub material: &'a Material,
     pub t: f32,
     pub front_face: bool,
+    pub uv: Vec2f, // surface parameterization at the hit point
+    // Unit vectors across the surface, along increasing u and v. They're at
+    // right angles to each other and to the outward normal, and don't flip
+    // with front_face.
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    // Set when this is the boundary of a ConstantMedium: the ray goes into
+    // the medium through a front face and out through a back one
+    pub medium: Option<&'a Medium>,
 }
 
-impl HitRecord{
+impl HitRecord<'_> {
     pub fn set_face_normal(&mut self, r: Ray, outward_normal: Vec3) -> (){
         self.front_face = Vec3::dot(r.dir, outward_normal) < 0.0;
         self.normal = if self.front_face { outward_normal } else { -outward_normal };
     }
+
+    // Square up the surface derivatives dp/du and dp/dv into tangent and
+    // bitangent. Where they don't say anything (the poles of a sphere), any
+    // pair of directions across the surface will do.
+    pub fn set_tangents(&mut self, outward_normal: Vec3, dpdu: Vec3, dpdv: Vec3) {
+        let n = outward_normal;
+        let t = dpdu - n * Vec3::dot(n, dpdu);
+        if t.near_zero() {
+            let basis = Onb::from_w(n);
+            self.tangent = basis.u;
+            self.bitangent = basis.v;
+            return;
+        }
+        self.tangent = Vec3::as_unit(t);
+        let b = Vec3::cross(n, self.tangent);
+        // uv mappings can be mirrored
+        self.bitangent = if Vec3::dot(b, dpdv) < 0.0 { -b } else { b };
+    }
+}
+
+// Where a point on the unit sphere lands in (u, v), with dp/du and dp/dv.
+// u goes once around the y axis starting from -x, v from the bottom (-y) to
+// the top, so an equirectangular map wraps on the right way up.
+fn sphere_uv(d: Vec3) -> (Vec2f, Vec3, Vec3) {
+    let theta = (-d.y).clamp(-1.0, 1.0).acos();
+    let phi = (-d.z).atan2(d.x) + std::f32::consts::PI;
+    let uv = Vec2f::new(phi / (2.0 * std::f32::consts::PI), theta / std::f32::consts::PI);
+    let dpdu = Vec3::new(d.z, 0.0, -d.x);
+    let dpdv = Vec3::new(-d.x * d.y, d.x * d.x + d.z * d.z, -d.z * d.y);
+    (uv, dpdu, dpdv)
+}
+
+fn hit_sphere<'a>(
+    center: Vec3,
+    radius: f32,
+    material: &'a Material,
+    r: Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<HitRecord<'a>> {
+    let oc = r.orig - center;
+    let a = r.dir.length_squared();
+    let half_b = Vec3::dot(oc, r.dir);
+    let c = oc.length_squared() - radius * radius;
+    let discriminant = half_b*half_b - a*c;
+
+    if discriminant < 0.0 {
+        return None;
+    }
+    let sqrtd = discriminant.sqrt();
+
+    // nearest root that lies within tolerance
+    let mut root = (-half_b - sqrtd) / a;
+    if root < t_min || root > t_max {
+        root = (-half_b + sqrtd) / a;
+        if root < t_min || root > t_max {
+            return None;
+        }
+    }
+    let p = r.at(root);
+    // a negative radius turns the normal inward (for hollow glass), the
+    // mapping stays the same
+    let (uv, dpdu, dpdv) = sphere_uv((p - center) / radius.abs());
+    let mut record = HitRecord{
+        p,
+        normal: (p - center) / radius,
+        material,
+        t: root,
+        front_face: false,
+        uv,
+        tangent: Vec3::zero(),
+        bitangent: Vec3::zero(),
+        medium: None,
+    };
+    let outward_normal = (record.p - center) / radius;
+    record.set_face_normal(r, outward_normal);
+    record.set_tangents(outward_normal, dpdu, dpdv);
+    Some(record)
+}
+
+// Where something is over time. The keys are (time, position) pairs sorted
+// by time, joined by straight lines, and it holds still before the first
+// and after the last. Two keys make a plain linear move.
+#[derive (Clone, Debug)]
+pub struct Keyframes {
+    keys: Vec<(f32, Vec3)>,
+}
+
+impl Keyframes {
+    // Needs at least one key
+    pub fn new(mut keys: Vec<(f32, Vec3)>) -> Keyframes {
+        assert!(!keys.is_empty(), "Keyframes need at least one key");
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Keyframes { keys }
+    }
+
+    pub fn linear(time0: f32, from: Vec3, time1: f32, to: Vec3) -> Keyframes {
+        Keyframes::new(vec![(time0, from), (time1, to)])
+    }
+
+    pub fn at(&self, time: f32) -> Vec3 {
+        // first key after time
+        let next = self.keys.partition_point(|key| key.0 <= time);
+        if next == 0 {
+            return self.keys[0].1;
+        }
+        if next == self.keys.len() {
+            return self.keys[next - 1].1;
+        }
+        let (t0, p0) = self.keys[next - 1];
+        let (t1, p1) = self.keys[next];
+        p0 + (p1 - p0) * ((time - t0) / (t1 - t0))
+    }
+
+    // Every position taken between time0 and time1: the ends, and the keys
+    // in between. Anything that moves along the lines between them stays
+    // inside the boxes around these.
+    pub fn positions(&self, time0: f32, time1: f32) -> impl Iterator<Item = Vec3> + '_ {
+        [self.at(time0), self.at(time1)].into_iter().chain(
+            self.keys.iter().filter(move |key| time0 < key.0 && key.0 < time1).map(|key| key.1)
+        )
+    }
 }
 
 #[derive (Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Hittable {
     Sphere { center: Vec3, radius: f32, material: Material },
-    HittableList { hittables: Vec<Hittable> }
+    // a sphere whose center follows a path, blurred by the camera's shutter
+    MovingSphere { path: Keyframes, radius: f32, material: Material },
+    Triangle { mesh: Arc<TriangleMesh>, face: usize, material: Material },
+    // Fog or smoke filling the inside of boundary. The boundary has to be
+    // closed, and media shouldn't overlap each other.
+    ConstantMedium { boundary: Box<Hittable>, medium: Medium },
+    HittableList { hittables: Vec<Hittable> },
+    BvhNode { bbox: Aabb, left: Box<Hittable>, right: Box<Hittable> },
 }
 
 impl Hittable {
-    pub fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         match self {
             Hittable::HittableList { hittables } => {
                 hittables.iter()
@@ -37,56 +182,215 @@ impl Hittable {
                 .min_by(|lhs, rhs| {
                     let lhs = lhs.as_ref().unwrap();
                     let rhs = rhs.as_ref().unwrap();
-                    lhs.t.partial_cmp(&rhs.t).expect("Couldn't compare??")
+                    lhs.t.total_cmp(&rhs.t)
                 }).unwrap_or(None)
             }
 
-            Hittable::Sphere { center, radius, material } => {
-                let oc = r.orig - *center;
-                let a = r.dir.length_squared();
-                let half_b = Vec3::dot(oc, r.dir);
-                let c = oc.length_squared() - radius * radius;
-                let discriminant = half_b*half_b - a*c;
-
-                if discriminant < 0.0 {
+            Hittable::BvhNode { bbox, left, right } => {
+                if !bbox.hit(r, t_min, t_max) {
                     return None;
                 }
-                let sqrtd = discriminant.sqrt();
-
-                // nearest root that lies within tolerance
-                let mut root = (-half_b - sqrtd) / a;
-                if root < t_min || root > t_max {
-                    root = (-half_b + sqrtd) / a;
-                    if root < t_min || root > t_max {
-                        return None;
-                    }
-                }
-                let mut record = HitRecord{
-                    p: r.at(root),
-                    normal: (r.at(root) - *center) / *radius,
-                    material: *material,
-                    t: root,
-                    front_face: false,
-                };
-                let outward_normal = (record.p - *center) / *radius;
-                record.set_face_normal(r, outward_normal);
+                // only accept right-side hits that are closer than the left one
+                let hit_left = left.hit(r, t_min, t_max);
+                let t_max = hit_left.as_ref().map_or(t_max, |rec| rec.t);
+                let hit_right = right.hit(r, t_min, t_max);
+                hit_right.or(hit_left)
+            }
+
+            Hittable::Triangle { mesh, face, material } => {
+                mesh.hit_face(*face, r, t_min, t_max, material)
+            }
+
+            Hittable::Sphere { center, radius, material } => {
+                hit_sphere(*center, *radius, material, r, t_min, t_max)
+            }
+
+            Hittable::MovingSphere { path, radius, material } => {
+                hit_sphere(path.at(r.time), *radius, material, r, t_min, t_max)
+            }
+
+            // Only the boundary gets hit here. What happens inside is up to
+            // the renderer, which knows when a ray is in the medium.
+            Hittable::ConstantMedium { boundary, medium } => {
+                let mut record = boundary.hit(r, t_min, t_max)?;
+                record.material = &medium.phase;
+                record.medium = Some(medium);
                 Some(record)
             }
         }
     }
+
+    // Box enclosing the whole object, or None if there isn't one (empty lists).
+    // Anything that moves reports a box covering everywhere it goes between
+    // time0 and time1. BVH nodes hand back the box they were built with.
+    pub fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        match self {
+            Hittable::Sphere { center, radius, .. } => {
+                let r = Vec3::ones() * radius.abs();
+                Some(Aabb::new(*center - r, *center + r))
+            }
+            Hittable::MovingSphere { path, radius, .. } => {
+                let r = Vec3::ones() * radius.abs();
+                path.positions(time0, time1)
+                    .map(|center| Aabb::new(center - r, center + r))
+                    .reduce(Aabb::surrounding)
+            }
+            Hittable::Triangle { mesh, face, .. } => Some(mesh.face_bounds(*face)),
+            Hittable::ConstantMedium { boundary, .. } => boundary.bounding_box(time0, time1),
+            Hittable::HittableList { hittables } => {
+                // members without a box can't be hit anyway, so skip them
+                hittables.iter()
+                .filter_map(|obj| obj.bounding_box(time0, time1))
+                .reduce(Aabb::surrounding)
+            }
+            Hittable::BvhNode { bbox, .. } => Some(*bbox),
+        }
+    }
+
+    // Fills boundary with a medium that scatters the same in every direction
+    pub fn constant_medium(boundary: Hittable, density: f32, albedo: Texture) -> Hittable {
+        Hittable::ConstantMedium {
+            boundary: Box::new(boundary),
+            medium: Medium { density, phase: Material::Isotropic { albedo } },
+        }
+    }
+
     pub fn push(&mut self, item: Hittable) {
         if let Hittable::HittableList { hittables } = self {
             hittables.push(item);
         }
     }
+
+    // Every primitive with an emissive material, for light sampling
+    pub fn emitters(&self) -> Vec<Hittable> {
+        match self {
+            Hittable::HittableList { hittables } => {
+                hittables.iter().flat_map(|obj| obj.emitters()).collect()
+            }
+            Hittable::BvhNode { left, right, .. } => {
+                let mut lights = left.emitters();
+                lights.extend(right.emitters());
+                lights
+            }
+            Hittable::Sphere { material, .. } | Hittable::Triangle { material, .. } => {
+                if material.is_emissive() { vec![self.clone()] } else { Vec::new() }
+            }
+            // Sampling these would need to know when. Paths that run into
+            // them still pick up their light.
+            Hittable::MovingSphere { .. } => Vec::new(),
+            // media scatter light, they don't make it
+            Hittable::ConstantMedium { .. } => Vec::new(),
+        }
+    }
+
+    // Random direction from origin toward a point on this object. Returns
+    // None when there's nothing useful to aim at (origin inside a sphere).
+    // Aggregates aren't sampled, ask Scene::sample_light instead.
+    pub fn sample_direction(&self, origin: Vec3, sampler: &mut Sampler) -> Option<Vec3> {
+        let Vec2f { x: r1, y: r2 } = sampler.get_2d();
+        match self {
+            Hittable::Sphere { center, radius, .. } => {
+                // uniform over the cone of directions the sphere covers
+                let to_center = *center - origin;
+                let dist_squared = to_center.length_squared();
+                if dist_squared <= radius * radius {
+                    return None;
+                }
+                let cos_theta_max = (1.0 - radius * radius / dist_squared).sqrt();
+                let z = 1.0 + r2 * (cos_theta_max - 1.0);
+                let phi = 2.0 * std::f32::consts::PI * r1;
+                let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+                let local = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z);
+                Some(Onb::from_w(to_center).local(local))
+            }
+            Hittable::Triangle { mesh, face, .. } => {
+                let point = mesh.sample_face(*face, r1, r2);
+                Some(Vec3::as_unit(point - origin))
+            }
+            _ => None,
+        }
+    }
+
+    // Probability density (per unit solid angle) of sample_direction picking
+    // dir from origin. Zero if dir doesn't hit the object at all.
+    pub fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        let ray = Ray { orig: origin, dir, time: 0.0 }; // lights hold still
+        let record = match self.hit(ray, 0.001, f32::INFINITY) {
+            Some(record) => record,
+            None => return 0.0,
+        };
+        match self {
+            Hittable::Sphere { center, radius, .. } => {
+                let dist_squared = (*center - origin).length_squared();
+                if dist_squared <= radius * radius {
+                    return 0.0;
+                }
+                let cos_theta_max = (1.0 - radius * radius / dist_squared).sqrt();
+                1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_theta_max))
+            }
+            Hittable::Triangle { mesh, face, .. } => {
+                // area measure to solid angle: distance^2 / (cosine * area)
+                let dist_squared = record.t * record.t * dir.length_squared();
+                let cosine = Vec3::dot(Vec3::as_unit(dir), mesh.face_normal(*face)).abs();
+                if cosine < 1e-6 {
+                    return 0.0;
+                }
+                dist_squared / (cosine * mesh.face_area(*face))
+            }
+            _ => 0.0,
+        }
+    }
 }
 
+// What fills a ConstantMedium. Density is the chance per unit of distance
+// that light running through gets scattered, and phase is the material it
+// scatters with (Isotropic).
+#[derive (Clone, Debug)]
+pub struct Medium {
+    pub density: f32,
+    pub phase: Material,
+}
+
+impl Medium {
+    // How far along r a ray gets before scattering, in units of t, from a
+    // uniform number in 0..1. Distances are exponentially distributed.
+    pub fn free_flight(&self, r: Ray, u: f32) -> f32 {
+        -(1.0 - u).ln() / (self.density * r.dir.length())
+    }
+
+    // Fraction of light making it through a stretch of the medium
+    pub fn transmittance(&self, distance: f32) -> f32 {
+        (-self.density * distance).exp()
+    }
 
-#[derive(Copy, Clone, Debug)]
+    // A scattering event at r.at(t), inside the medium. There's no surface,
+    // so the normal just faces back along the ray.
+    pub fn scatter_record(&self, r: Ray, t: f32) -> HitRecord<'_> {
+        let p = r.at(t);
+        let normal = -Vec3::as_unit(r.dir);
+        let basis = Onb::from_w(normal);
+        HitRecord {
+            p,
+            normal,
+            material: &self.phase,
+            t,
+            front_face: true,
+            uv: Vec2f::zero(),
+            tangent: basis.u,
+            bitangent: basis.v,
+            medium: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Material{
-    Lambertian { albedo: Vec3 },
-    Metal { albedo:Vec3, fuzz: f32 },
+    Lambertian { albedo: Texture },
+    Metal { albedo: Texture, fuzz: Texture }, // fuzz is the roughness, 0 to 1
     Dielectric { index_refraction: f32 },
+    DiffuseLight { emit: Texture },
+    // scatters evenly over the whole sphere of directions, for media
+    Isotropic { albedo: Texture },
 }
 
 impl Material {
@@ -96,11 +400,11 @@ impl Material {
         rec: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
-        srng: &mut SmallRng,
+        sampler: &mut Sampler,
     ) -> bool {
         match self {
             Material::Lambertian { albedo } => {
-                let scatter_dir = rec.normal + Vec3::rand_unit_vector(srng);
+                let scatter_dir = rec.normal + sampler::unit_vector(sampler.get_2d());
                 // The compiler might be smart enough to compute this ^^^ just once. In which case,
                 // I don't need to do this weird dance. Oh well. It'll work.
                 let scatter_dir = if scatter_dir.near_zero() {  // if near zero,
@@ -114,21 +418,24 @@ impl Material {
                 // using them at all)
                 *scattered = Ray{
                     orig: rec.p,
-                    dir: scatter_dir
+                    dir: scatter_dir,
+                    time: ray_in.time,
                 };
-                *attenuation = *albedo; // deref on both sides? Wacky
+                *attenuation = albedo.value(rec.uv, rec.p);
                 return true;
             },
             Material::Metal { albedo, fuzz } => {
+                let fuzz = fuzz.scalar(rec.uv, rec.p).clamp(0.0, 1.0);
                 let reflected = Vec3::reflect(
                     Vec3::as_unit(ray_in.dir),
                     rec.normal
                 );
                 *scattered = Ray{
                     orig: rec.p,
-                    dir: reflected + Vec3::rand_in_unit_sphere(srng) * *fuzz,
+                    dir: reflected + sampler::in_unit_sphere(sampler.get_2d(), sampler.get_1d()) * fuzz,
+                    time: ray_in.time,
                 };
-                *attenuation = *albedo;
+                *attenuation = albedo.value(rec.uv, rec.p);
                 return Vec3::dot(scattered.dir, rec.normal) > 0.0;
             },
             Material::Dielectric { index_refraction } => {
@@ -140,18 +447,65 @@ impl Material {
                 let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
                 let cannot_refract = refraction_ratio * sin_theta > 1.0;
-                let distrib_zero_one = Uniform::new(0.0, 1.0);
-                let direction = if cannot_refract || Material::reflectance(cos_theta, refraction_ratio) > srng.sample(distrib_zero_one) {
+                let direction = if cannot_refract || Material::reflectance(cos_theta, refraction_ratio) > sampler.get_1d() {
                     Vec3::reflect(unit_direction, rec.normal)
                 } else {
                     Vec3::refract(unit_direction, rec.normal, refraction_ratio)
                 };
                 *scattered = Ray {
                     orig: rec.p,
-                    dir: direction 
+                    dir: direction,
+                    time: ray_in.time,
                 };
                 return true;
             },
+            Material::DiffuseLight { .. } => false, // lights absorb everything
+            Material::Isotropic { albedo } => {
+                *scattered = Ray {
+                    orig: rec.p,
+                    dir: sampler::unit_vector(sampler.get_2d()),
+                    time: ray_in.time,
+                };
+                *attenuation = albedo.value(rec.uv, rec.p);
+                true
+            },
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        matches!(self, Material::DiffuseLight { .. })
+    }
+
+    // Mirror-like materials only scatter in (roughly) one direction, so
+    // sampling a light from them would never line up. They skip eval().
+    pub fn is_specular(&self) -> bool {
+        matches!(self, Material::Metal { .. } | Material::Dielectric { .. })
+    }
+
+    // For materials that can be evaluated in any direction (not mirrors or
+    // glass), gives the BSDF times the cosine term toward dir, along with the
+    // density scatter() would have picked dir with.
+    pub fn eval(&self, rec: &HitRecord, dir: Vec3) -> Option<(Vec3, f32)> {
+        match self {
+            Material::Lambertian { albedo } => {
+                let cosine = Vec3::dot(rec.normal, Vec3::as_unit(dir)).max(0.0);
+                let pdf = cosine / std::f32::consts::PI;
+                Some((albedo.value(rec.uv, rec.p) * pdf, pdf))
+            }
+            // no cosine term, there's no surface to be at an angle to
+            Material::Isotropic { albedo } => {
+                let pdf = 1.0 / (4.0 * std::f32::consts::PI);
+                Some((albedo.value(rec.uv, rec.p) * pdf, pdf))
+            }
+            _ => None,
+        }
+    }
+
+    // Light given off by the surface, seen from either side
+    pub fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        match self {
+            Material::DiffuseLight { emit } => emit.value(rec.uv, rec.p),
+            _ => Vec3::zero(),
         }
     }
 
@@ -169,6 +523,7 @@ pub fn degrees_to_radians(degrees: f32) -> f32 {
     degrees * std::f32::consts::PI / 180.0
 }
 
+#[derive (Copy, Clone)]
 pub struct Camera {
     origin: Vec3,
     lower_left_corner: Vec3,
@@ -176,6 +531,9 @@ pub struct Camera {
     vertical: Vec3,
     u: Vec3, v: Vec3, /*w: Vec3,*/
     lens_radius: f32,
+    // shutter open and close times; rays are sent at random in between
+    time0: f32,
+    time1: f32,
 }
 
 impl Camera {
@@ -209,33 +567,121 @@ impl Camera {
             vertical: verti,
             u, v, /* w,*/
             lens_radius: aperture / 2.0,
+            time0: 0.0,
+            time1: 0.0,
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32, srng: &mut SmallRng) -> Ray {
-        let rd = Vec3::rand_in_unit_disk(srng) * self.lens_radius;
+    // The same camera with its shutter open from time0 to time1, for motion
+    // blur. Cameras start out with an instant exposure at time 0.
+    pub fn with_shutter(&self, time0: f32, time1: f32) -> Camera {
+        Camera { time0, time1, ..*self }
+    }
+
+    pub fn shutter(&self) -> (f32, f32) {
+        (self.time0, self.time1)
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.horizontal.length() / self.vertical.length()
+    }
+
+    // The same camera with its horizontal field of view widened or narrowed
+    // to suit a different image shape. The vertical field of view stays put.
+    pub fn with_aspect_ratio(&self, aspect_ratio: f32) -> Camera {
+        let current = self.aspect_ratio();
+        let center = self.lower_left_corner + self.horizontal / 2.0 + self.vertical / 2.0;
+        let horizontal = self.horizontal * (aspect_ratio / current);
+        Camera {
+            lower_left_corner: center - horizontal / 2.0 - self.vertical / 2.0,
+            horizontal,
+            ..*self
+        }
+    }
+
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut Sampler) -> Ray {
+        let rd = sampler::in_unit_disk(sampler.get_2d()) * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
 
         let dir = self.lower_left_corner
                 + self.horizontal * s
                 + self.vertical * t 
                 - self.origin - offset;
+        // only spend a dimension on the time when there's a choice, so
+        // still pictures come out the same as ever
+        let time = if self.time1 > self.time0 {
+            self.time0 + sampler.get_1d() * (self.time1 - self.time0)
+        } else {
+            self.time0
+        };
         Ray{
             orig: self.origin + offset,
             dir,
+            time,
         }
     }
 }
 
 
+// What a ray sees when it escapes the scene
+#[derive (Copy, Clone, Debug)]
+pub enum Background {
+    Sky,                    // the white-to-blue gradient from the book
+    Solid { color: Vec3 },  // flat color; black turns the sky off entirely
+}
+
+const SKY_COLOR: Vec3 = Vec3 { x: 0.5, y: 0.7, z: 1.0};
+
+impl Background {
+    pub fn color(&self, r: Ray) -> Vec3 {
+        match self {
+            Background::Sky => {
+                let unitdir = Vec3::as_unit(r.dir);
+                let t = 0.5 * (unitdir.y + 1.0);
+                Vec3::ones() * (1.0 - t) + SKY_COLOR * t
+            }
+            Background::Solid { color } => *color,
+        }
+    }
+}
+
 pub struct Scene {
     pub camera: Camera,
     pub world: Hittable,
+    pub background: Background,
+    pub lights: Vec<Hittable>, // emissive primitives, sampled directly while rendering
 }
 
 impl Scene {
-    pub fn random_world(srng: &mut SmallRng) -> Hittable {
-        let mat_ground = Material::Lambertian { albedo: Vec3::new(0.5, 0.5, 0.5) };
+    // Builds the scene, collecting the light list from the world
+    pub fn new(camera: Camera, world: Hittable, background: Background) -> Scene {
+        let lights = world.emitters();
+        Scene { camera, world, background, lights }
+    }
+
+    // Pick a light uniformly and a direction toward it
+    pub fn sample_light(&self, origin: Vec3, sampler: &mut Sampler) -> Option<Vec3> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index = (sampler.get_1d() * self.lights.len() as f32) as usize;
+        self.lights[index.min(self.lights.len() - 1)].sample_direction(origin, sampler)
+    }
+
+    // Density of sample_light choosing dir, over all the lights
+    pub fn light_pdf(&self, origin: Vec3, dir: Vec3) -> f32 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let total: f32 = self.lights.iter().map(|light| light.pdf_value(origin, dir)).sum();
+        total / self.lights.len() as f32
+    }
+
+    // ground replaces the plain grey the book uses, e.g. with a checker.
+    // bouncing sends the small diffuse spheres up by as much as 0.5 between
+    // times 0 and 1, like the second book does to show off motion blur.
+    pub fn random_world(srng: &mut SmallRng, ground: Option<Material>, bouncing: bool) -> Hittable {
+        let mat_ground = ground.unwrap_or(Material::Lambertian { albedo: Texture::solid(Vec3::new(0.5, 0.5, 0.5)) });
         let mut world = Hittable::HittableList { hittables : Vec::<Hittable>::new() };
         
         world.push( Hittable::Sphere { center: Vec3::new(0.0, -1000.0, 0.0), radius: 1000.0, material: mat_ground });
@@ -254,14 +700,25 @@ impl Scene {
                     if choose_mat < 0.8 {
                         // diffuse
                         let albedo = Vec3::rand(srng, distrib_zero_one) * Vec3::rand(srng, distrib_zero_one);
-                        let sphere_material = Material::Lambertian { albedo };
-                        world.push(
-                            Hittable::Sphere {
-                                center,
-                                radius: 0.2,
-                                material: sphere_material,
-                            }
-                        );
+                        let sphere_material = Material::Lambertian { albedo: Texture::solid(albedo) };
+                        if bouncing {
+                            let top = center + Vec3::new(0.0, 0.5 * srng.sample(distrib_zero_one), 0.0);
+                            world.push(
+                                Hittable::MovingSphere {
+                                    path: Keyframes::linear(0.0, center, 1.0, top),
+                                    radius: 0.2,
+                                    material: sphere_material,
+                                }
+                            );
+                        } else {
+                            world.push(
+                                Hittable::Sphere {
+                                    center,
+                                    radius: 0.2,
+                                    material: sphere_material,
+                                }
+                            );
+                        }
                     } else if choose_mat < 0.95 {
                         // metal
                         let distr_albedo = Uniform::new(0.5, 1.0);
@@ -269,7 +726,7 @@ impl Scene {
 
                         let albedo = Vec3::rand(srng, distr_albedo);
                         let fuzz = srng.sample(distr_fuzz);
-                        let material = Material::Metal { albedo, fuzz };
+                        let material = Material::Metal { albedo: Texture::solid(albedo), fuzz: Texture::constant(fuzz) };
                         world.push(
                             Hittable::Sphere {
                                 center,
@@ -300,14 +757,14 @@ impl Scene {
             material: material1
         });
 
-        let material2 = Material::Lambertian { albedo: Vec3::new(0.4, 0.2, 0.1) };
+        let material2 = Material::Lambertian { albedo: Texture::solid(Vec3::new(0.4, 0.2, 0.1)) };
         world.push( Hittable::Sphere {
             center: Vec3::new(-4.0, 1.0, 0.0),
             radius: 1.0,
             material: material2
         });
 
-        let material3 = Material::Metal { albedo: Vec3::new(0.7, 0.6, 0.5), fuzz: 0.0 };
+        let material3 = Material::Metal { albedo: Texture::solid(Vec3::new(0.7, 0.6, 0.5)), fuzz: Texture::constant(0.0) };
         world.push( Hittable::Sphere {
             center: Vec3::new(4.0, 1.0, 0.0),
             radius: 1.0,
@@ -315,4 +772,242 @@ impl Scene {
         });
         world
     }
-}
\ No newline at end of file
+
+    // The Cornell box, lit by a single area light in the ceiling. Goes with
+    // Scene::cornell_camera() and a black background.
+    pub fn cornell_box() -> Hittable {
+        let red = Material::Lambertian { albedo: Texture::solid(Vec3::new(0.65, 0.05, 0.05)) };
+        let white = Material::Lambertian { albedo: Texture::solid(Vec3::new(0.73, 0.73, 0.73)) };
+        let green = Material::Lambertian { albedo: Texture::solid(Vec3::new(0.12, 0.45, 0.15)) };
+        let light = Material::DiffuseLight { emit: Texture::solid(Vec3::new(15.0, 15.0, 15.0)) };
+
+        let x = Vec3::new(555.0, 0.0, 0.0);
+        let y = Vec3::new(0.0, 555.0, 0.0);
+        let z = Vec3::new(0.0, 0.0, 555.0);
+
+        let mut world = Hittable::HittableList { hittables: Vec::new() };
+        world.push(Hittable::quad(x, y, z, green));                 // left wall
+        world.push(Hittable::quad(Vec3::zero(), y, z, red));        // right wall
+        world.push(Hittable::quad(Vec3::zero(), x, z, white.clone())); // floor
+        world.push(Hittable::quad(y, x, z, white.clone()));         // ceiling
+        world.push(Hittable::quad(z, x, y, white.clone()));         // back wall
+        world.push(Hittable::quad(
+            Vec3::new(343.0, 554.0, 332.0),
+            Vec3::new(-130.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -105.0),
+            light,
+        ));
+
+        world.push(Hittable::cuboid(
+            Vec3::new(265.0, 0.0, 295.0), Vec3::new(165.0, 330.0, 165.0), 15.0, white.clone()
+        ));
+        world.push(Hittable::cuboid(
+            Vec3::new(130.0, 0.0, 65.0), Vec3::new(165.0, 165.0, 165.0), -18.0, white
+        ));
+        world
+    }
+
+    pub fn cornell_camera(aspect_ratio: f32) -> Camera {
+        Camera::new(
+            Vec3::new(278.0, 278.0, -800.0), // lookfrom
+            Vec3::new(278.0, 278.0, 0.0), // lookat
+            Vec3::new(0.0, 1.0, 0.0), // vup
+            40.0,
+            aspect_ratio,
+            0.0, // aperture
+            10.0, // dist_to_focus
+        )
+    }
+
+    pub fn random_world_camera(aspect_ratio: f32) -> Camera {
+        Camera::new(
+            Vec3::new(13.0, 2.0, 3.0), // lookfrom
+            Vec3::zero(), // lookat
+            Vec3::new(0.0, 1.0, 0.0), // vup
+            20.0,
+            aspect_ratio,
+            0.1, // aperture
+            10.0, // dist_to_focus
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Vec2i;
+    use crate::sampler::SamplerKind;
+
+    // Averaging 1/pdf over sampled directions estimates the solid angle the
+    // light covers, which is easy to know for these shapes.
+    fn estimated_solid_angle(light: &Hittable, origin: Vec3) -> f32 {
+        let samples = 4000;
+        let total: f32 = (0..samples).map(|i| {
+            let mut sampler = Sampler::new(SamplerKind::Independent, 1, Vec2i { x: 0, y: 0 }, i, samples);
+            let dir = light.sample_direction(origin, &mut sampler).expect("light should be visible");
+            let pdf = light.pdf_value(origin, dir);
+            assert!(pdf > 0.0, "sampled direction missed the light");
+            1.0 / pdf
+        }).sum();
+        total / samples as f32
+    }
+
+    #[test]
+    fn sphere_light_pdf() {
+        let light = Hittable::Sphere {
+            center: Vec3::new(0.0, 5.0, 0.0),
+            radius: 1.0,
+            material: Material::DiffuseLight { emit: Texture::solid(Vec3::ones()) },
+        };
+        let cos_theta_max = (1.0f32 - 1.0 / 25.0).sqrt();
+        let expected = 2.0 * std::f32::consts::PI * (1.0 - cos_theta_max);
+        let estimate = estimated_solid_angle(&light, Vec3::zero());
+        assert!((estimate - expected).abs() < expected * 0.01, "{} vs {}", estimate, expected);
+    }
+
+    #[test]
+    fn triangle_light_pdf() {
+        // small enough that the solid angle is close to area / distance^2
+        let light = Hittable::triangle(
+            Vec3::new(0.0, 10.0, 0.0),
+            Vec3::new(0.5, 10.0, 0.0),
+            Vec3::new(0.0, 10.0, 0.5),
+            Material::DiffuseLight { emit: Texture::solid(Vec3::ones()) },
+        );
+        let expected = 0.125 / 100.0;
+        let estimate = estimated_solid_angle(&light, Vec3::zero());
+        assert!((estimate - expected).abs() < expected * 0.02, "{} vs {}", estimate, expected);
+    }
+
+    #[test]
+    fn sphere_uv_and_tangents() {
+        let sphere = Hittable::Sphere {
+            center: Vec3::new(1.0, 0.0, 0.0),
+            radius: 2.0,
+            material: Material::Dielectric { index_refraction: 1.5 },
+        };
+        let hit_from = |dir: Vec3| {
+            let ray = Ray { orig: Vec3::new(1.0, 0.0, 0.0) + dir * 10.0, dir: -dir, time: 0.0 };
+            sphere.hit(ray, 0.001, f32::INFINITY).expect("should hit the sphere")
+        };
+        let close = |a: Vec2f, b: Vec2f| (a.x - b.x).abs() < 1e-5 && (a.y - b.y).abs() < 1e-5;
+
+        let rec = hit_from(Vec3::new(1.0, 0.0, 0.0));
+        assert!(close(rec.uv, Vec2f::new(0.5, 0.5)), "{:?}", rec.uv);
+        assert!((rec.tangent - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-5);
+        assert!((rec.bitangent - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-5);
+        assert!(close(hit_from(Vec3::new(0.0, 0.0, -1.0)).uv, Vec2f::new(0.75, 0.5)));
+        assert!((hit_from(Vec3::new(0.0, 1.0, 0.0)).uv.y - 1.0).abs() < 1e-5);
+        assert!(hit_from(Vec3::new(0.0, -1.0, 0.0)).uv.y.abs() < 1e-5);
+
+        for dir in [Vec3::new(0.3, 0.5, -0.8), Vec3::new(-0.6, -0.2, 0.1), Vec3::new(0.0, 1.0, 0.0)] {
+            let rec = hit_from(Vec3::as_unit(dir));
+            let outward = Vec3::as_unit(rec.p - Vec3::new(1.0, 0.0, 0.0));
+            assert!(Vec3::dot(rec.tangent, outward).abs() < 1e-5);
+            assert!(Vec3::dot(rec.bitangent, outward).abs() < 1e-5);
+            assert!(Vec3::dot(rec.tangent, rec.bitangent).abs() < 1e-5);
+            assert!((rec.tangent.length() - 1.0).abs() < 1e-5);
+        }
+
+        // stepping along the tangent raises u, along the bitangent raises v
+        let rec = hit_from(Vec3::as_unit(Vec3::new(0.3, 0.5, -0.8)));
+        let step = |offset: Vec3| {
+            let d = Vec3::as_unit(rec.p + offset * 0.01 - Vec3::new(1.0, 0.0, 0.0));
+            hit_from(d).uv
+        };
+        assert!(step(rec.tangent).x > rec.uv.x);
+        assert!(step(rec.bitangent).y > rec.uv.y);
+    }
+
+    #[test]
+    fn keyframes_interpolate() {
+        let path = Keyframes::new(vec![
+            (1.0, Vec3::new(2.0, 0.0, 0.0)),
+            (0.0, Vec3::zero()),
+            (2.0, Vec3::new(2.0, 4.0, 0.0)),
+        ]);
+        assert_eq!(path.at(-1.0), Vec3::zero());
+        assert_eq!(path.at(0.25), Vec3::new(0.5, 0.0, 0.0));
+        assert_eq!(path.at(1.5), Vec3::new(2.0, 2.0, 0.0));
+        assert_eq!(path.at(7.0), Vec3::new(2.0, 4.0, 0.0));
+        // the corner at time 1 is inside the interval, so it counts
+        let positions: Vec<Vec3> = path.positions(0.5, 1.5).collect();
+        assert_eq!(positions, [Vec3::new(1.0, 0.0, 0.0), Vec3::new(2.0, 2.0, 0.0), Vec3::new(2.0, 0.0, 0.0)]);
+    }
+
+    #[test]
+    fn moving_sphere_moves() {
+        let sphere = Hittable::MovingSphere {
+            path: Keyframes::linear(0.0, Vec3::zero(), 1.0, Vec3::new(0.0, 4.0, 0.0)),
+            radius: 1.0,
+            material: Material::Dielectric { index_refraction: 1.5 },
+        };
+        let ray = |y: f32, time: f32| Ray { orig: Vec3::new(0.0, y, -5.0), dir: Vec3::new(0.0, 0.0, 1.0), time };
+        assert!(sphere.hit(ray(0.0, 0.0), 0.001, f32::INFINITY).is_some());
+        assert!(sphere.hit(ray(0.0, 1.0), 0.001, f32::INFINITY).is_none());
+        assert!(sphere.hit(ray(2.0, 0.5), 0.001, f32::INFINITY).is_some());
+        assert_eq!(
+            sphere.bounding_box(0.0, 0.5),
+            Some(Aabb::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 3.0, 1.0)))
+        );
+        // moving lights aren't sampled directly
+        assert!(sphere.emitters().is_empty());
+
+        // rays go out while the shutter is open
+        let camera = Scene::random_world_camera(1.5).with_shutter(0.25, 0.5);
+        for i in 0..20 {
+            let mut sampler = Sampler::new(SamplerKind::Independent, 1, Vec2i { x: 0, y: 0 }, i, 20);
+            let time = camera.get_ray(0.5, 0.5, &mut sampler).time;
+            assert!((0.25..=0.5).contains(&time), "{}", time);
+        }
+    }
+
+    #[test]
+    fn constant_medium_thins_light() {
+        let fog = Hittable::constant_medium(
+            Hittable::Sphere { center: Vec3::zero(), radius: 1.0, material: Material::Dielectric { index_refraction: 1.0 } },
+            0.7,
+            Texture::solid(Vec3::new(0.5, 0.5, 0.5)),
+        );
+        // the boundary is what gets hit, wearing the medium's material
+        let ray = Ray { orig: Vec3::new(0.0, 0.0, -5.0), dir: Vec3::new(0.0, 0.0, 2.0), time: 0.0 };
+        let rec = fog.hit(ray, 0.001, f32::INFINITY).unwrap();
+        assert!(rec.front_face && (rec.t - 2.0).abs() < 1e-5);
+        let medium = rec.medium.expect("boundary should lead into the medium");
+        assert!(matches!(rec.material, Material::Isotropic { .. }));
+        assert!(fog.emitters().is_empty());
+
+        // the share of free flights that make it across the 2 unit chord
+        // should match the transmittance
+        let inside = Ray { orig: rec.p, ..ray };
+        let samples = 20000;
+        let through = (0..samples).filter(|i| {
+            let mut sampler = Sampler::new(SamplerKind::Independent, 1, Vec2i { x: 0, y: 0 }, *i, samples);
+            // t is in units of the unnormalized direction, half a unit each
+            medium.free_flight(inside, sampler.get_1d()) > 1.0
+        }).count();
+        let expected = medium.transmittance(2.0);
+        assert!((expected - (-1.4f32).exp()).abs() < 1e-6);
+        let measured = through as f32 / samples as f32;
+        assert!((measured - expected).abs() < 0.01, "{} vs {}", measured, expected);
+
+        // the phase function is the same every way, and integrates to one
+        let scatter = medium.scatter_record(inside, 0.5);
+        assert_eq!(scatter.p, Vec3::new(0.0, 0.0, 0.0));
+        let (f, pdf) = medium.phase.eval(&scatter, Vec3::new(1.0, 2.0, 3.0)).unwrap();
+        assert!((pdf * 4.0 * std::f32::consts::PI - 1.0).abs() < 1e-6);
+        assert_eq!(f, Vec3::new(0.5, 0.5, 0.5) * pdf);
+        assert_eq!(medium.phase.eval(&scatter, -ray.dir).unwrap().1, pdf);
+    }
+
+    #[test]
+    fn scene_collects_lights() {
+        let scene = Scene::new(
+            Scene::cornell_camera(1.0),
+            Scene::cornell_box().into_bvh(0.0, 1.0),
+            Background::Solid { color: Vec3::zero() },
+        );
+        // the ceiling light is a single quad
+        assert_eq!(scene.lights.len(), 2);
+    }
+}
@@ -0,0 +1,935 @@
+
+// Scene description files
+//
+// Scenes are written in TOML: a [camera] table taking the arguments of
+// Camera::new, a [render] table for the image size, RenderProperties (sampler
+// included) and tone mapping, an optional [background], named [textures.*]
+// and [materials.*], and a list of [[objects]] that refer to materials by
+// name. Material colors are either [r, g, b] or the name of a texture, and
+// so is a metal's fuzz (a number or a texture). See scenes/ for examples.
+
+use crate::primitives::{Vec2i, Vec3};
+use crate::scene::{Background, Camera, Hittable, Keyframes, Material, Scene};
+use crate::renderer::{self, AdaptiveProperties, RenderProperties};
+use crate::filter::{self, Filter, FilterKind};
+use crate::sampler::SamplerKind;
+use crate::tonemap::{ToneCurve, ToneMap};
+use crate::obj::{self, ObjError};
+use crate::texture::{Image, Texture, WrapMode};
+use crate::noise::{NoisePattern, Perlin};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use serde::Deserialize;
+use toml::Spanned;
+
+#[derive (Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    camera: Spanned<CameraDesc>,
+    render: Spanned<RenderDesc>,
+    #[serde(default)]
+    background: BackgroundDesc,
+    #[serde(default)]
+    textures: HashMap<String, Spanned<TextureDesc>>,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialDesc>>,
+    #[serde(default)]
+    objects: Vec<Spanned<ObjectDesc>>,
+}
+
+#[derive (Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    lookfrom: [f32; 3],
+    lookat: [f32; 3],
+    #[serde(default = "default_vup")]
+    vup: [f32; 3],
+    vfov: f32,
+    #[serde(default)]
+    aperture: f32,
+    // defaults to the distance between lookfrom and lookat
+    focus_dist: Option<f32>,
+    // open and close times, for motion blur
+    #[serde(default)]
+    shutter: [f32; 2],
+}
+
+fn default_vup() -> [f32; 3] { [0.0, 1.0, 0.0] }
+
+#[derive (Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenderDesc {
+    width: i32,
+    height: i32,
+    #[serde(default = "default_samples")]
+    samples: u32,
+    #[serde(default = "default_bounces")]
+    bounces: u32,
+    // bounces before Russian roulette may end a path
+    #[serde(default = "default_roulette_depth")]
+    roulette_depth: u32,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    sampler: SamplerKind,
+    // reconstruction filter, and how far it reaches if not the usual
+    #[serde(default)]
+    filter: FilterKind,
+    filter_radius: Option<f32>,
+    // turns on adaptive sampling, with samples as the most any pixel gets
+    adaptive_threshold: Option<f32>,
+    #[serde(default = "default_min_samples")]
+    min_samples: u32,
+    // stops of exposure and the curve used for 8-bit output
+    #[serde(default)]
+    exposure: f32,
+    #[serde(default)]
+    tonemap: ToneCurve,
+}
+
+fn default_samples() -> u32 { 10 }
+fn default_bounces() -> u32 { 50 }
+fn default_roulette_depth() -> u32 { 3 }
+fn default_min_samples() -> u32 { 16 }
+
+#[derive (Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum BackgroundDesc {
+    #[default]
+    Sky,
+    Solid { color: [f32; 3] },
+}
+
+// A plain color, or the name of a texture
+#[derive (Deserialize)]
+#[serde(untagged)]
+enum ColorDesc {
+    Rgb([f32; 3]),
+    Texture(String),
+}
+
+// Same again for plain numbers
+#[derive (Deserialize)]
+#[serde(untagged)]
+enum NumberDesc {
+    Value(f32),
+    Texture(String),
+}
+
+impl Default for NumberDesc {
+    fn default() -> Self {
+        NumberDesc::Value(0.0)
+    }
+}
+
+#[derive (Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDesc {
+    Solid { color: [f32; 3] },
+    Checker {
+        even: ColorDesc,
+        odd: ColorDesc,
+        #[serde(default = "default_checker_scale")]
+        scale: f32,
+    },
+    // PNG or PPM, path relative to the scene file. srgb = false is for
+    // images that hold data rather than colors.
+    Image {
+        path: String,
+        #[serde(default)]
+        wrap: WrapMode,
+        #[serde(default = "default_srgb")]
+        srgb: bool,
+    },
+    // Perlin noise shaped by the pattern: fbm, turbulence, marble or wood.
+    // low and high default to colors that suit the pattern. seed is added
+    // to the render seed, to tell apart textures that would otherwise match.
+    Noise {
+        pattern: NoisePattern,
+        #[serde(default = "default_noise_scale")]
+        scale: f32,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u64,
+        low: Option<ColorDesc>,
+        high: Option<ColorDesc>,
+    },
+}
+
+fn default_checker_scale() -> f32 { 1.0 }
+fn default_srgb() -> bool { true }
+fn default_noise_scale() -> f32 { 1.0 }
+fn default_octaves() -> u32 { 7 }
+
+#[derive (Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDesc {
+    Lambertian { albedo: ColorDesc },
+    Metal { albedo: ColorDesc, #[serde(default)] fuzz: NumberDesc },
+    Dielectric { index_refraction: f32 },
+    DiffuseLight { emit: ColorDesc },
+}
+
+#[derive (Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectDesc {
+    Sphere { center: [f32; 3], radius: f32, material: String },
+    // follows the keyframes in a straight line from one to the next
+    MovingSphere { keyframes: Vec<KeyframeDesc>, radius: f32, material: String },
+    Triangle { vertices: [[f32; 3]; 3], material: String },
+    Quad { corner: [f32; 3], u: [f32; 3], v: [f32; 3], material: String },
+    Cuboid {
+        corner: [f32; 3],
+        size: [f32; 3],
+        #[serde(default)]
+        angle: f32,
+        material: String,
+    },
+    // Wavefront OBJ file, path relative to the scene file. Materials come
+    // from the OBJ's own material libraries.
+    Obj { path: String },
+    // Fog or smoke filling a shape. density is how thick it is (the chance
+    // of scattering per unit of distance), albedo the color it scatters.
+    ConstantMedium { boundary: BoundaryDesc, density: f32, albedo: ColorDesc },
+    // The final scene from the first book, generated from the render seed.
+    // ground names a material to use for the ground instead of plain grey,
+    // and bouncing sets the small spheres moving, as in the second book.
+    RandomWorld {
+        ground: Option<String>,
+        #[serde(default)]
+        bouncing: bool,
+    },
+}
+
+// The shape of a medium, like the objects of the same name without a material
+#[derive (Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum BoundaryDesc {
+    Sphere { center: [f32; 3], radius: f32 },
+    Cuboid {
+        corner: [f32; 3],
+        size: [f32; 3],
+        #[serde(default)]
+        angle: f32,
+    },
+}
+
+#[derive (Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyframeDesc {
+    time: f32,
+    center: [f32; 3],
+}
+
+#[derive (Debug)]
+pub enum SceneError {
+    Io { path: PathBuf, source: io::Error },
+    Parse { file: String, source: toml::de::Error },
+    Invalid { file: String, line: usize, message: String },
+    Obj(ObjError),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            SceneError::Parse { file, source } => write!(f, "{}: {}", file, source),
+            SceneError::Invalid { file, line, message } => write!(f, "{}:{}: {}", file, line, message),
+            SceneError::Obj(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<ObjError> for SceneError {
+    fn from(err: ObjError) -> Self {
+        SceneError::Obj(err)
+    }
+}
+
+// Everything a scene file describes
+pub struct SceneFile {
+    pub scene: Scene,
+    pub properties: RenderProperties,
+    pub image_size: Vec2i,
+    pub tone_map: ToneMap,
+    // every other file the scene was built from: OBJ, MTL and texture images
+    pub files: Vec<PathBuf>,
+}
+
+fn vec3(a: [f32; 3]) -> Vec3 {
+    Vec3::new(a[0], a[1], a[2])
+}
+
+// Builds SceneErrors that point at the right line of the source
+struct Validator<'a> {
+    source: &'a str,
+    file: &'a str,
+}
+
+impl Validator<'_> {
+    fn line_of(&self, span: std::ops::Range<usize>) -> usize {
+        let start = span.start.min(self.source.len());
+        self.source[..start].matches('\n').count() + 1
+    }
+
+    fn error(&self, span: std::ops::Range<usize>, message: String) -> SceneError {
+        SceneError::Invalid { file: self.file.to_string(), line: self.line_of(span), message }
+    }
+
+    fn check(&self, ok: bool, span: std::ops::Range<usize>, message: impl FnOnce() -> String) -> Result<(), SceneError> {
+        if ok { Ok(()) } else { Err(self.error(span, message())) }
+    }
+}
+
+// NaN or infinite coordinates would poison the BVH boxes
+fn finite(values: &[f32]) -> bool {
+    values.iter().all(|x| x.is_finite())
+}
+
+fn color_ok(c: [f32; 3]) -> bool {
+    c.iter().all(|x| x.is_finite() && *x >= 0.0)
+}
+
+// Builds named textures as they're asked for, each only once. Textures can
+// refer to each other, so this also watches for loops.
+struct Textures<'a> {
+    descs: &'a HashMap<String, Spanned<TextureDesc>>,
+    base_dir: &'a Path,
+    seed: u64, // for noise
+    files: Vec<PathBuf>, // images loaded so far
+    built: HashMap<&'a str, Arc<Texture>>,
+    building: Vec<&'a str>,
+}
+
+impl<'a> Textures<'a> {
+    // A color parameter as a texture. `what` names the parameter for errors.
+    fn color(&mut self, desc: &'a ColorDesc, what: &str, span: std::ops::Range<usize>, v: &Validator) -> Result<Texture, SceneError> {
+        match desc {
+            ColorDesc::Rgb(color) => {
+                v.check(color_ok(*color), span, || format!("{} can't be negative", what))?;
+                Ok(Texture::solid(vec3(*color)))
+            }
+            ColorDesc::Texture(name) => Ok(self.get(name, what, span, v)?.as_ref().clone()),
+        }
+    }
+
+    // A number between 0 and 1, or a texture that gives one
+    fn number(&mut self, desc: &'a NumberDesc, what: &str, span: std::ops::Range<usize>, v: &Validator) -> Result<Texture, SceneError> {
+        match desc {
+            NumberDesc::Value(value) => {
+                v.check((0.0..=1.0).contains(value), span, || format!("{} must be between 0 and 1, got {}", what, value))?;
+                Ok(Texture::constant(*value))
+            }
+            NumberDesc::Texture(name) => Ok(self.get(name, what, span, v)?.as_ref().clone()),
+        }
+    }
+
+    fn get(&mut self, name: &'a str, what: &str, span: std::ops::Range<usize>, v: &Validator) -> Result<Arc<Texture>, SceneError> {
+        if let Some(texture) = self.built.get(name) {
+            return Ok(texture.clone());
+        }
+        let (name, desc) = self.descs.get_key_value(name)
+            .ok_or_else(|| v.error(span.clone(), format!("{}: unknown texture '{}'", what, name)))?;
+        v.check(!self.building.contains(&name.as_str()), span, || format!("Texture '{}' refers back to itself", name))?;
+        self.building.push(name);
+
+        let span = desc.span();
+        let what = format!("Texture '{}'", name);
+        let texture = match desc.get_ref() {
+            TextureDesc::Solid { color } => {
+                v.check(color_ok(*color), span, || format!("{}: color can't be negative", what))?;
+                Texture::solid(vec3(*color))
+            }
+            TextureDesc::Checker { even, odd, scale } => {
+                v.check(*scale > 0.0, span.clone(), || format!("{}: scale must be positive, got {}", what, scale))?;
+                Texture::Checker {
+                    even: Arc::new(self.color(even, &what, span.clone(), v)?),
+                    odd: Arc::new(self.color(odd, &what, span, v)?),
+                    scale: *scale,
+                }
+            }
+            TextureDesc::Image { path, wrap, srgb } => {
+                let path = self.base_dir.join(path);
+                let image = Image::load(&path, *srgb).map_err(|message| {
+                    v.error(span, format!("{}: couldn't load {}: {}", what, path.display(), message))
+                })?;
+                self.files.push(path);
+                Texture::Image { image: Arc::new(image), wrap: *wrap }
+            }
+            TextureDesc::Noise { pattern, scale, octaves, seed, low, high } => {
+                v.check(*scale > 0.0, span.clone(), || format!("{}: scale must be positive, got {}", what, scale))?;
+                v.check((1..=16).contains(octaves), span.clone(), || {
+                    format!("{}: octaves must be between 1 and 16, got {}", what, octaves)
+                })?;
+                let perlin = Arc::new(Perlin::new(self.seed.wrapping_add(*seed)));
+                let (default_low, default_high) = pattern.default_colors();
+                let mut color = |desc: &'a Option<ColorDesc>, default: Vec3| -> Result<Arc<Texture>, SceneError> {
+                    Ok(Arc::new(match desc {
+                        Some(desc) => self.color(desc, &what, span.clone(), v)?,
+                        None => Texture::solid(default),
+                    }))
+                };
+                Texture::Noise {
+                    perlin,
+                    pattern: *pattern,
+                    scale: *scale,
+                    octaves: *octaves,
+                    low: color(low, default_low)?,
+                    high: color(high, default_high)?,
+                }
+            }
+        };
+        self.building.pop();
+        let texture = Arc::new(texture);
+        self.built.insert(name, texture.clone());
+        Ok(texture)
+    }
+}
+
+fn build_material<'a>(
+    desc: &'a Spanned<MaterialDesc>,
+    name: &str,
+    textures: &mut Textures<'a>,
+    v: &Validator,
+) -> Result<Material, SceneError> {
+    let span = desc.span();
+    let what = |param: &str| format!("Material '{}': {}", name, param);
+    Ok(match desc.get_ref() {
+        MaterialDesc::Lambertian { albedo } => {
+            Material::Lambertian { albedo: textures.color(albedo, &what("albedo"), span, v)? }
+        }
+        MaterialDesc::Metal { albedo, fuzz } => {
+            Material::Metal {
+                albedo: textures.color(albedo, &what("albedo"), span.clone(), v)?,
+                fuzz: textures.number(fuzz, &what("fuzz"), span, v)?,
+            }
+        }
+        MaterialDesc::Dielectric { index_refraction } => {
+            v.check(*index_refraction > 0.0, span, || {
+                format!("Material '{}': index_refraction must be positive, got {}", name, index_refraction)
+            })?;
+            Material::Dielectric { index_refraction: *index_refraction }
+        }
+        MaterialDesc::DiffuseLight { emit } => {
+            Material::DiffuseLight { emit: textures.color(emit, &what("emit"), span, v)? }
+        }
+    })
+}
+
+// Parse scene source. `file` is used for error messages, and base_dir for
+// finding any files the scene refers to.
+pub fn parse_scene(source: &str, file: &str, base_dir: &Path) -> Result<SceneFile, SceneError> {
+    let desc: SceneDesc = toml::from_str(source)
+        .map_err(|source| SceneError::Parse { file: file.to_string(), source })?;
+    let v = Validator { source, file };
+
+    let span = desc.render.span();
+    let render = desc.render.get_ref();
+    v.check(render.width > 0 && render.height > 0, span.clone(), || {
+        format!("Image size must be positive, got {}x{}", render.width, render.height)
+    })?;
+    v.check(renderer::image_size_ok(render.width, render.height), span.clone(), || {
+        format!("A {}x{} image is too big, the most is {} pixels", render.width, render.height, renderer::MAX_PIXELS)
+    })?;
+    v.check(render.samples > 0, span.clone(), || "samples must be at least 1".to_string())?;
+    v.check(render.exposure.is_finite(), span.clone(), || "exposure must be a finite number of stops".to_string())?;
+    v.check(render.adaptive_threshold.is_none_or(|t| t > 0.0), span.clone(), || {
+        "adaptive_threshold must be positive".to_string()
+    })?;
+    v.check(render.min_samples >= 2, span.clone(), || "min_samples must be at least 2".to_string())?;
+    v.check(render.filter_radius.is_none_or(|r| r > 0.0 && r <= filter::MAX_RADIUS), span, || {
+        format!("filter_radius must be above 0 and at most {} pixels", filter::MAX_RADIUS)
+    })?;
+
+    let span = desc.camera.span();
+    let camera = desc.camera.get_ref();
+    v.check(camera.vfov > 0.0 && camera.vfov < 180.0, span.clone(), || {
+        format!("Camera vfov must be between 0 and 180 degrees, got {}", camera.vfov)
+    })?;
+    v.check(camera.aperture.is_finite() && camera.aperture >= 0.0, span.clone(), || {
+        "Camera aperture must be finite and can't be negative".to_string()
+    })?;
+    v.check(finite(&camera.lookfrom) && finite(&camera.lookat) && finite(&camera.vup), span.clone(), || {
+        "Camera lookfrom, lookat and vup must be finite".to_string()
+    })?;
+    let lookfrom = vec3(camera.lookfrom);
+    let lookat = vec3(camera.lookat);
+    let vup = vec3(camera.vup);
+    v.check(lookfrom != lookat, span.clone(), || "Camera lookfrom and lookat are the same point".to_string())?;
+    // the camera can't tell which way is up when looking straight along vup
+    v.check(
+        !vup.near_zero() && !Vec3::cross(Vec3::as_unit(vup), Vec3::as_unit(lookfrom - lookat)).near_zero(),
+        span.clone(),
+        || "Camera vup can't be zero or point along the view direction".to_string(),
+    )?;
+    let focus_dist = camera.focus_dist.unwrap_or((lookfrom - lookat).length());
+    v.check(focus_dist.is_finite() && focus_dist > 0.0, span.clone(), || {
+        "Camera focus_dist must be positive and finite".to_string()
+    })?;
+    let [time0, time1] = camera.shutter;
+    v.check(time0.is_finite() && time1.is_finite() && time0 <= time1, span, || {
+        "Camera shutter must open before it closes".to_string()
+    })?;
+
+    let mut textures = Textures {
+        descs: &desc.textures,
+        base_dir,
+        seed: render.seed,
+        files: Vec::new(),
+        built: HashMap::new(),
+        building: Vec::new(),
+    };
+    let mut materials = HashMap::new();
+    for (name, material) in &desc.materials {
+        materials.insert(name.as_str(), build_material(material, name, &mut textures, &v)?);
+    }
+    let mut world = Hittable::HittableList { hittables: Vec::new() };
+    let mut obj_files = Vec::new();
+    for object in &desc.objects {
+        // Spans don't survive inside the tagged object tables, so problems
+        // with an object are reported at the start of its table.
+        let span = object.span();
+        let lookup = |name: &String| -> Result<Material, SceneError> {
+            materials.get(name.as_str()).cloned().ok_or_else(|| {
+                v.error(object.span(), format!("Unknown material '{}'", name))
+            })
+        };
+        match object.get_ref() {
+            ObjectDesc::Sphere { center, radius, material } => {
+                v.check(finite(center) && radius.is_finite(), span.clone(), || "Sphere center and radius must be finite".to_string())?;
+                v.check(*radius > 0.0, span, || format!("Sphere radius must be positive, got {}", radius))?;
+                world.push(Hittable::Sphere { center: vec3(*center), radius: *radius, material: lookup(material)? });
+            }
+            ObjectDesc::MovingSphere { keyframes, radius, material } => {
+                v.check(radius.is_finite(), span.clone(), || "Sphere radius must be finite".to_string())?;
+                v.check(*radius > 0.0, span.clone(), || format!("Sphere radius must be positive, got {}", radius))?;
+                v.check(!keyframes.is_empty(), span.clone(), || "Moving sphere needs at least one keyframe".to_string())?;
+                v.check(
+                    keyframes.iter().all(|key| key.time.is_finite() && finite(&key.center)),
+                    span,
+                    || "Moving sphere keyframe times and centers must be finite".to_string(),
+                )?;
+                world.push(Hittable::MovingSphere {
+                    path: Keyframes::new(keyframes.iter().map(|key| (key.time, vec3(key.center))).collect()),
+                    radius: *radius,
+                    material: lookup(material)?,
+                });
+            }
+            ObjectDesc::Triangle { vertices, material } => {
+                v.check(finite(vertices.as_flattened()), span.clone(), || "Triangle vertices must be finite".to_string())?;
+                let [a, b, c] = vertices.map(vec3);
+                v.check(!Vec3::cross(b - a, c - a).near_zero(), span, || "Triangle has no area".to_string())?;
+                world.push(Hittable::triangle(a, b, c, lookup(material)?));
+            }
+            ObjectDesc::Quad { corner, u, v: side, material } => {
+                v.check(finite(corner) && finite(u) && finite(side), span.clone(), || {
+                    "Quad corner, u and v must be finite".to_string()
+                })?;
+                v.check(!Vec3::cross(vec3(*u), vec3(*side)).near_zero(), span, || "Quad has no area".to_string())?;
+                world.push(Hittable::quad(vec3(*corner), vec3(*u), vec3(*side), lookup(material)?));
+            }
+            ObjectDesc::Cuboid { corner, size, angle, material } => {
+                v.check(finite(corner) && finite(size) && angle.is_finite(), span.clone(), || {
+                    "Cuboid corner, size and angle must be finite".to_string()
+                })?;
+                v.check(size.iter().all(|s| *s > 0.0), span, || "Cuboid size must be positive".to_string())?;
+                world.push(Hittable::cuboid(vec3(*corner), vec3(*size), *angle, lookup(material)?));
+            }
+            ObjectDesc::Obj { path } => {
+                world.push(obj::load_obj(base_dir.join(path), &mut obj_files)?);
+            }
+            ObjectDesc::ConstantMedium { boundary, density, albedo } => {
+                v.check(density.is_finite() && *density > 0.0, span.clone(), || {
+                    format!("Medium density must be positive, got {}", density)
+                })?;
+                let albedo = textures.color(albedo, "Medium albedo", span.clone(), &v)?;
+                // the boundary's own material never shows, the medium's stands in
+                let phase = Material::Isotropic { albedo: albedo.clone() };
+                let boundary = match boundary {
+                    BoundaryDesc::Sphere { center, radius } => {
+                        v.check(finite(center) && radius.is_finite(), span.clone(), || {
+                            "Sphere center and radius must be finite".to_string()
+                        })?;
+                        v.check(*radius > 0.0, span, || format!("Sphere radius must be positive, got {}", radius))?;
+                        Hittable::Sphere { center: vec3(*center), radius: *radius, material: phase }
+                    }
+                    BoundaryDesc::Cuboid { corner, size, angle } => {
+                        v.check(finite(corner) && finite(size) && angle.is_finite(), span.clone(), || {
+                            "Cuboid corner, size and angle must be finite".to_string()
+                        })?;
+                        v.check(size.iter().all(|s| *s > 0.0), span, || "Cuboid size must be positive".to_string())?;
+                        Hittable::cuboid(vec3(*corner), vec3(*size), *angle, phase)
+                    }
+                };
+                world.push(Hittable::constant_medium(boundary, *density, albedo));
+            }
+            ObjectDesc::RandomWorld { ground, bouncing } => {
+                let ground = ground.as_ref().map(lookup).transpose()?;
+                let mut rng = SmallRng::seed_from_u64(render.seed);
+                world.push(Scene::random_world(&mut rng, ground, *bouncing));
+            }
+        }
+    }
+
+    let background = match desc.background {
+        BackgroundDesc::Sky => Background::Sky,
+        BackgroundDesc::Solid { color } => Background::Solid { color: vec3(color) },
+    };
+    let camera = Camera::new(
+        lookfrom,
+        lookat,
+        vup,
+        camera.vfov,
+        render.width as f32 / render.height as f32,
+        camera.aperture,
+        focus_dist,
+    ).with_shutter(time0, time1);
+
+    Ok(SceneFile {
+        scene: Scene::new(camera, world.into_bvh(time0, time1), background),
+        properties: RenderProperties {
+            samples: render.samples,
+            bounces: render.bounces,
+            roulette_depth: render.roulette_depth,
+            seed: render.seed,
+            adaptive: render.adaptive_threshold.map(|threshold| AdaptiveProperties {
+                min_samples: render.min_samples,
+                threshold,
+            }),
+            sampler: render.sampler,
+            filter: Filter {
+                kind: render.filter,
+                radius: render.filter_radius.unwrap_or(render.filter.default_radius()),
+            },
+        },
+        image_size: Vec2i { x: render.width, y: render.height },
+        tone_map: ToneMap { exposure: render.exposure, curve: render.tonemap },
+        files: textures.files.into_iter().chain(obj_files).collect(),
+    })
+}
+
+pub fn load_scene(path: impl AsRef<Path>) -> Result<SceneFile, SceneError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path)
+        .map_err(|source| SceneError::Io { path: path.to_path_buf(), source })?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    parse_scene(&source, &path.display().to_string(), base_dir)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::{Aabb, Ray};
+
+    fn parse(source: &str) -> Result<SceneFile, SceneError> {
+        parse_scene(source, "test.toml", Path::new("."))
+    }
+
+    fn error_line(source: &str) -> usize {
+        match parse(source) {
+            Err(SceneError::Invalid { line, .. }) => line,
+            Err(e) => panic!("Wrong kind of error: {}", e),
+            Ok(_) => panic!("Expected an error"),
+        }
+    }
+
+    const HEADER: &str = "
+[camera]
+lookfrom = [0.0, 0.0, -5.0]
+lookat = [0.0, 0.0, 0.0]
+vfov = 40.0
+
+[render]
+width = 40
+height = 20
+";
+
+    #[test]
+    fn example_scenes_load() {
+        let cornell = parse_scene(include_str!("../scenes/cornell.toml"), "cornell.toml", Path::new("scenes"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(cornell.image_size, Vec2i { x: 400, y: 400 });
+        assert_eq!(cornell.scene.lights.len(), 2);
+        assert_eq!(cornell.tone_map.curve, ToneCurve::Aces);
+        assert_eq!(cornell.properties.sampler, SamplerKind::Sobol);
+
+        let random = parse_scene(include_str!("../scenes/random_world.toml"), "random_world.toml", Path::new("scenes"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(random.properties.samples, 10);
+
+        parse_scene(include_str!("../scenes/noise.toml"), "noise.toml", Path::new("scenes"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        let bouncing = parse_scene(include_str!("../scenes/bouncing_spheres.toml"), "bouncing_spheres.toml", Path::new("scenes"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(bouncing.scene.camera.shutter(), (0.0, 1.0));
+
+        let smoke = parse_scene(include_str!("../scenes/cornell_smoke.toml"), "cornell_smoke.toml", Path::new("scenes"))
+            .unwrap_or_else(|e| panic!("{}", e));
+        // the smoke doesn't count as a light
+        assert_eq!(smoke.scene.lights.len(), 2);
+    }
+
+    #[test]
+    fn defaults_fill_in() {
+        let file = parse(HEADER).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(file.properties.samples, 10);
+        assert_eq!(file.properties.bounces, 50);
+        assert!(file.properties.adaptive.is_none());
+        assert!(matches!(file.scene.background, Background::Sky));
+        assert_eq!(file.tone_map, ToneMap::default());
+    }
+
+    #[test]
+    fn unknown_material_reports_line() {
+        let source = format!("{}{}", HEADER, "
+[materials.red]
+type = \"lambertian\"
+albedo = [0.8, 0.1, 0.1]
+
+[[objects]]
+type = \"sphere\"
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+material = \"blue\"
+");
+        assert_eq!(error_line(&source), 15);
+        let err = parse(&source).err().unwrap();
+        assert_eq!(err.to_string(), "test.toml:15: Unknown material 'blue'");
+    }
+
+    #[test]
+    fn bad_values_rejected() {
+        let source = format!("{}{}", HEADER, "
+[materials.steel]
+type = \"metal\"
+albedo = [0.8, 0.8, 0.8]
+fuzz = 2.0
+");
+        assert_eq!(error_line(&source), 11);
+
+        let source = HEADER.replace("width = 40", "width = 0");
+        assert_eq!(error_line(&source), 7);
+        assert!(parse(&source).err().unwrap().to_string().contains("Image size must be positive"));
+        let source = HEADER.replace("width = 40", "width = 70000").replace("height = 20", "height = 70000");
+        assert_eq!(error_line(&source), 7);
+        assert!(parse(&source).err().unwrap().to_string().contains("too big"));
+
+        let source = HEADER.replace("vfov = 40.0", "vfov = 0.0");
+        assert_eq!(error_line(&source), 2);
+
+        // looking straight down with the default vup
+        let source = HEADER.replace("lookfrom = [0.0, 0.0, -5.0]", "lookfrom = [0.0, 5.0, 0.0]");
+        assert_eq!(error_line(&source), 2);
+        assert!(parse(&source).err().unwrap().to_string().contains("vup"));
+        let source = HEADER.replace("vfov = 40.0", "vfov = 40.0\nvup = [0.0, 0.0, 0.0]");
+        assert_eq!(error_line(&source), 2);
+        let source = HEADER.replace("lookat = [0.0, 0.0, 0.0]", "lookat = [0.0, nan, 0.0]");
+        assert_eq!(error_line(&source), 2);
+
+        let source = HEADER.replace("height = 20", "height = 20\nadaptive_threshold = -0.1");
+        assert_eq!(error_line(&source), 7);
+
+        let source = HEADER.replace("height = 20", "height = 20\nfilter = \"gaussian\"\nfilter_radius = 0.0");
+        assert_eq!(error_line(&source), 7);
+    }
+
+    #[test]
+    fn geometry_must_be_finite() {
+        let object = |body: &str| format!("{}{}{}", HEADER, "
+[materials.m]
+type = \"lambertian\"
+albedo = [0.5, 0.5, 0.5]
+
+[[objects]]
+", body);
+        let cases = [
+            "type = \"sphere\"\ncenter = [nan, 0.0, 0.0]\nradius = 1.0\nmaterial = \"m\"",
+            "type = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = inf\nmaterial = \"m\"",
+            "type = \"moving_sphere\"\nradius = inf\nmaterial = \"m\"\nkeyframes = [{ time = 0.0, center = [0.0, 0.0, 0.0] }]",
+            "type = \"triangle\"\nvertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, inf, 0.0]]\nmaterial = \"m\"",
+            "type = \"quad\"\ncorner = [0.0, 0.0, nan]\nu = [1.0, 0.0, 0.0]\nv = [0.0, 1.0, 0.0]\nmaterial = \"m\"",
+            "type = \"cuboid\"\ncorner = [0.0, 0.0, 0.0]\nsize = [1.0, inf, 1.0]\nmaterial = \"m\"",
+            "type = \"cuboid\"\ncorner = [0.0, 0.0, 0.0]\nsize = [1.0, 1.0, 1.0]\nangle = nan\nmaterial = \"m\"",
+            "type = \"constant_medium\"\ndensity = 0.1\nalbedo = [1.0, 1.0, 1.0]\nboundary = { type = \"sphere\", center = [0.0, 0.0, 0.0], radius = inf }",
+            "type = \"constant_medium\"\ndensity = 0.1\nalbedo = [1.0, 1.0, 1.0]\nboundary = { type = \"cuboid\", corner = [nan, 0.0, 0.0], size = [1.0, 1.0, 1.0] }",
+        ];
+        for case in cases {
+            assert_eq!(error_line(&object(case)), 15, "{}", case);
+        }
+        // and a finite one of each kind still loads
+        parse(&object("type = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"m\""))
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        assert_eq!(error_line(&HEADER.replace("vfov = 40.0", "vfov = 40.0\naperture = inf")), 2);
+        assert_eq!(error_line(&HEADER.replace("vfov = 40.0", "vfov = 40.0\nfocus_dist = inf")), 2);
+    }
+
+    #[test]
+    fn textures_resolve() {
+        let source = format!("{}{}", HEADER, "
+[textures.white]
+type = \"solid\"
+color = [1.0, 1.0, 1.0]
+
+[textures.tiles]
+type = \"checker\"
+even = \"white\"
+odd = [0.0, 0.0, 0.0]
+scale = 2.0
+
+[materials.floor]
+type = \"lambertian\"
+albedo = \"tiles\"
+
+[[objects]]
+type = \"random_world\"
+ground = \"floor\"
+");
+        parse(&source).unwrap_or_else(|e| panic!("{}", e));
+
+        let unknown = source.replace("albedo = \"tiles\"", "albedo = \"marble\"");
+        assert_eq!(
+            parse(&unknown).err().unwrap().to_string(),
+            "test.toml:21: Material 'floor': albedo: unknown texture 'marble'"
+        );
+
+        let looped = source.replace("even = \"white\"", "even = \"tiles\"");
+        assert!(parse(&looped).err().unwrap().to_string().contains("Texture 'tiles' refers back to itself"));
+
+        let missing = source.replace("type = \"solid\"\ncolor = [1.0, 1.0, 1.0]", "type = \"image\"\npath = \"no_such_file.png\"");
+        assert_eq!(error_line(&missing), 11);
+        assert!(parse(&missing).err().unwrap().to_string().contains("couldn't load"));
+    }
+
+    #[test]
+    fn referenced_files_listed() {
+        let dir = std::env::temp_dir().join(format!("rustpt_scene_files_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("dot.ppm"), "P3 1 1 1 1 1 1").unwrap();
+        fs::write(dir.join("tri.obj"), "mtllib tri.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n").unwrap();
+        fs::write(dir.join("tri.mtl"), "newmtl red\nKd 1 0 0\n").unwrap();
+        let source = format!("{}{}", HEADER, "
+[textures.dot]
+type = \"image\"
+path = \"dot.ppm\"
+
+[materials.dotted]
+type = \"lambertian\"
+albedo = \"dot\"
+
+[[objects]]
+type = \"obj\"
+path = \"tri.obj\"
+");
+        let file = parse_scene(&source, "test.toml", &dir);
+        let listed = file.as_ref().map(|file| file.files.clone());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            listed.unwrap_or_else(|e| panic!("{}", e)),
+            vec![dir.join("dot.ppm"), dir.join("tri.obj"), dir.join("tri.mtl")]
+        );
+    }
+
+    #[test]
+    fn moving_spheres() {
+        let source = format!("{}{}", HEADER, "
+[materials.red]
+type = \"lambertian\"
+albedo = [0.8, 0.1, 0.1]
+
+[[objects]]
+type = \"moving_sphere\"
+radius = 0.5
+material = \"red\"
+keyframes = [
+    { time = 0.0, center = [0.0, 0.0, 0.0] },
+    { time = 0.5, center = [1.0, 0.0, 0.0] },
+    { time = 1.0, center = [1.0, 1.0, 0.0] },
+]
+").replace("vfov = 40.0", "vfov = 40.0\nshutter = [0.0, 1.0]");
+        let file = parse(&source).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(
+            file.scene.world.bounding_box(0.0, 1.0),
+            Some(Aabb::new(Vec3::new(-0.5, -0.5, -0.5), Vec3::new(1.5, 1.5, 0.5)))
+        );
+
+        assert_eq!(error_line(&source.replace("shutter = [0.0, 1.0]", "shutter = [1.0, 0.0]")), 2);
+        assert_eq!(error_line(&source.replace(
+            "keyframes = [\n    { time = 0.0, center = [0.0, 0.0, 0.0] },\n    { time = 0.5, center = [1.0, 0.0, 0.0] },\n    { time = 1.0, center = [1.0, 1.0, 0.0] },\n]",
+            "keyframes = []",
+        )), 16);
+        assert_eq!(error_line(&source.replace("time = 0.5", "time = nan")), 16);
+        assert_eq!(error_line(&source.replace("center = [1.0, 1.0, 0.0]", "center = [1.0, inf, 0.0]")), 16);
+    }
+
+    #[test]
+    fn noise_textures() {
+        let source = format!("{}{}", HEADER, "
+[textures.veins]
+type = \"noise\"
+pattern = \"marble\"
+octaves = 4
+high = [1.0, 1.0, 1.0]
+
+[materials.stone]
+type = \"metal\"
+albedo = \"veins\"
+fuzz = \"veins\"
+");
+        parse(&source).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(error_line(&source.replace("octaves = 4", "octaves = 0")), 11);
+
+        let wrong = source.replace("\"marble\"", "\"granite\"");
+        assert!(matches!(parse(&wrong), Err(SceneError::Parse { .. })));
+    }
+
+    #[test]
+    fn constant_media() {
+        let source = format!("{}{}", HEADER, "
+[[objects]]
+type = \"constant_medium\"
+density = 0.5
+albedo = [0.9, 0.9, 0.9]
+boundary = { type = \"sphere\", center = [0.0, 0.0, 0.0], radius = 1.0 }
+");
+        let file = parse(&source).unwrap_or_else(|e| panic!("{}", e));
+        let ray = Ray { orig: Vec3::new(0.0, 0.0, -5.0), dir: Vec3::new(0.0, 0.0, 1.0), time: 0.0 };
+        let rec = file.scene.world.hit(ray, 0.001, f32::INFINITY).expect("should hit the boundary");
+        assert_eq!(rec.medium.map(|m| m.density), Some(0.5));
+
+        assert_eq!(error_line(&source.replace("density = 0.5", "density = 0.0")), 11);
+        assert_eq!(error_line(&source.replace("radius = 1.0", "radius = -1.0")), 11);
+        // boundaries don't take a material
+        let wrong = source.replace("radius = 1.0 }", "radius = 1.0, material = \"x\" }");
+        assert!(matches!(parse(&wrong), Err(SceneError::Parse { .. })));
+    }
+
+    #[test]
+    fn syntax_and_type_errors() {
+        let source = format!("{}{}", HEADER, "
+[[objects]]
+type = \"sphere\"
+center = [0.0, 0.0]
+radius = 1.0
+material = \"x\"
+");
+        assert!(matches!(parse(&source), Err(SceneError::Parse { .. })));
+
+        let source = format!("{}{}", HEADER, "
+[[objects]]
+type = \"teapot\"
+");
+        assert!(matches!(parse(&source), Err(SceneError::Parse { .. })));
+    }
+}
@@ -0,0 +1,287 @@
+
+// Textures, for material colors that change over a surface
+//
+// A texture is looked up with the hit's surface coordinates (uv) and its
+// position in the world. Solid colors, checkers and noise only care about
+// the position, images only about the uv.
+//
+// Textures also stand in for plain numbers, like a metal's roughness. Those
+// take the average of the three channels.
+
+use crate::primitives::{Vec2f, Vec3};
+use crate::noise::{NoisePattern, Perlin};
+use crate::png;
+use crate::tonemap::srgb_decode;
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+#[derive (Clone, Debug)]
+pub enum Texture {
+    Solid { color: Vec3 },
+    // alternating cubes through space, `scale` units on a side
+    Checker { even: Arc<Texture>, odd: Arc<Texture>, scale: f32 },
+    Image { image: Arc<Image>, wrap: WrapMode },
+    // from low to high as the pattern goes from 0 to 1, with the hit point
+    // multiplied by scale first
+    Noise {
+        perlin: Arc<Perlin>,
+        pattern: NoisePattern,
+        scale: f32,
+        octaves: u32,
+        low: Arc<Texture>,
+        high: Arc<Texture>,
+    },
+}
+
+impl Texture {
+    pub fn solid(color: Vec3) -> Texture {
+        Texture::Solid { color }
+    }
+
+    // The same number everywhere, for inputs that aren't colors
+    pub fn constant(v: f32) -> Texture {
+        Texture::Solid { color: Vec3::new(v, v, v) }
+    }
+
+    pub fn value(&self, uv: Vec2f, p: Vec3) -> Vec3 {
+        match self {
+            Texture::Solid { color } => *color,
+            Texture::Checker { even, odd, scale } => {
+                let cell = |x: f32| (x / scale).floor() as i64;
+                if (cell(p.x) + cell(p.y) + cell(p.z)) % 2 == 0 {
+                    even.value(uv, p)
+                } else {
+                    odd.value(uv, p)
+                }
+            }
+            Texture::Image { image, wrap } => image.sample(uv, *wrap),
+            Texture::Noise { perlin, pattern, scale, octaves, low, high } => {
+                let t = pattern.value(perlin, p * *scale, *octaves);
+                low.value(uv, p) * (1.0 - t) + high.value(uv, p) * t
+            }
+        }
+    }
+
+    pub fn scalar(&self, uv: Vec2f, p: Vec3) -> f32 {
+        let v = self.value(uv, p);
+        (v.x + v.y + v.z) / 3.0
+    }
+}
+
+// What happens to uv coordinates outside 0..1
+#[derive (Copy, Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WrapMode {
+    #[default]
+    Repeat,
+    Clamp, // the edge pixels carry on forever
+    Mirror, // every other copy is flipped
+}
+
+impl WrapMode {
+    // Texel index i moved into 0..n
+    fn apply(&self, i: i64, n: i64) -> usize {
+        let i = match self {
+            WrapMode::Repeat => i.rem_euclid(n),
+            WrapMode::Clamp => i.clamp(0, n - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n { i } else { 2 * n - 1 - i }
+            }
+        };
+        i as usize
+    }
+}
+
+// Linear RGB pixels, top row first
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+}
+
+// The pixels would drown out everything else
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Image {{ {}x{} }}", self.width, self.height)
+    }
+}
+
+impl Image {
+    // From 0..1 values as they come out of a file. 8-bit images are nearly
+    // always sRGB encoded; data like roughness maps aren't.
+    pub fn from_rgb(width: usize, height: usize, rgb: &[f32], srgb: bool) -> Image {
+        let decode = |v: f32| if srgb { srgb_decode(v) } else { v };
+        let pixels = rgb.chunks(3)
+            .map(|c| Vec3::new(decode(c[0]), decode(c[1]), decode(c[2])))
+            .collect();
+        Image { width, height, pixels }
+    }
+
+    // PNG or PPM (binary or plain), told apart by their first bytes
+    pub fn load(path: impl AsRef<Path>, srgb: bool) -> Result<Image, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        let (width, height, rgb) = if bytes.starts_with(b"\x89PNG") {
+            png::read_png(&bytes)?
+        } else if bytes.starts_with(b"P3") || bytes.starts_with(b"P6") {
+            read_ppm(&bytes)?
+        } else {
+            return Err("not a PNG or PPM image".to_string());
+        };
+        Ok(Image::from_rgb(width as usize, height as usize, &rgb, srgb))
+    }
+
+    fn texel(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[y * self.width + x]
+    }
+
+    // Bilinear lookup. v runs up the image, so v = 0 is the bottom row.
+    // Texel centres sit half a texel in from the edges.
+    pub fn sample(&self, uv: Vec2f, wrap: WrapMode) -> Vec3 {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        if !(x.is_finite() && y.is_finite()) {
+            return Vec3::zero();
+        }
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (w, h) = (self.width as i64, self.height as i64);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let (xa, xb) = (wrap.apply(x0, w), wrap.apply(x0 + 1, w));
+        let (ya, yb) = (wrap.apply(y0, h), wrap.apply(y0 + 1, h));
+        let top = self.texel(xa, ya) * (1.0 - fx) + self.texel(xb, ya) * fx;
+        let bottom = self.texel(xa, yb) * (1.0 - fx) + self.texel(xb, yb) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+// P3 or P6, as RGB floats in 0..1, top row first
+fn read_ppm(bytes: &[u8]) -> Result<(u32, u32, Vec<f32>), String> {
+    // header fields are separated by whitespace, with # starting a comment
+    let mut at = 2;
+    let mut field = || -> Result<u32, String> {
+        loop {
+            match bytes.get(at) {
+                Some(b'#') => {
+                    while bytes.get(at).is_some_and(|&b| b != b'\n') {
+                        at += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => at += 1,
+                _ => break,
+            }
+        }
+        let start = at;
+        while bytes.get(at).is_some_and(|b| b.is_ascii_digit()) {
+            at += 1;
+        }
+        std::str::from_utf8(&bytes[start..at]).unwrap()
+            .parse().map_err(|_| "bad PPM header".to_string())
+    };
+    let (width, height, max) = (field()?, field()?, field()?);
+    if width == 0 || height == 0 || (width as u64 * height as u64) > (1 << 28) {
+        return Err(format!("unusable image size {}x{}", width, height));
+    }
+    if max == 0 || max > 65535 {
+        return Err(format!("PPM maximum value must be 1 to 65535, got {}", max));
+    }
+    let count = width as usize * height as usize * 3;
+
+    let values: Vec<u32> = if bytes[1] == b'6' {
+        // exactly one whitespace byte after the header, then raw samples
+        let data = bytes.get(at + 1..).unwrap_or(&[]);
+        if max < 256 {
+            data.iter().take(count).map(|&b| b as u32).collect()
+        } else {
+            data.chunks_exact(2).take(count).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32).collect()
+        }
+    } else {
+        std::str::from_utf8(&bytes[at..]).map_err(|_| "PPM has non-text pixel data".to_string())?
+            .split_whitespace()
+            .take(count)
+            .map(|v| v.parse().map_err(|_| format!("bad PPM value '{}'", v)))
+            .collect::<Result<_, _>>()?
+    };
+    if values.len() < count {
+        return Err("PPM pixel data is cut short".to_string());
+    }
+    let rgb = values.iter().map(|&v| v.min(max) as f32 / max as f32).collect();
+    Ok((width, height, rgb))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn two_by_two() -> Image {
+        // top row black and red, bottom row green and blue
+        Image {
+            width: 2,
+            height: 2,
+            pixels: vec![
+                Vec3::zero(), Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0),
+            ],
+        }
+    }
+
+    #[test]
+    fn bilinear_and_wrapping() {
+        let image = two_by_two();
+        // texel centres give the texels back, v = 0 is the bottom
+        assert_eq!(image.sample(Vec2f::new(0.25, 0.75), WrapMode::Repeat), Vec3::zero());
+        assert_eq!(image.sample(Vec2f::new(0.75, 0.25), WrapMode::Repeat), Vec3::new(0.0, 0.0, 1.0));
+        // halfway between all four
+        let middle = image.sample(Vec2f::new(0.5, 0.5), WrapMode::Clamp);
+        assert!((middle - Vec3::new(0.25, 0.25, 0.25)).length() < 1e-6);
+
+        // at the left edge: repeat blends with the right column, clamp doesn't
+        let edge = Vec2f::new(0.0, 0.75);
+        assert_eq!(image.sample(edge, WrapMode::Repeat), Vec3::new(0.5, 0.0, 0.0));
+        assert_eq!(image.sample(edge, WrapMode::Clamp), Vec3::zero());
+        assert_eq!(image.sample(edge, WrapMode::Mirror), Vec3::zero());
+        // one whole image over, repeat is back where it started, mirror is flipped
+        let over = Vec2f::new(1.25, 0.75);
+        assert_eq!(image.sample(over, WrapMode::Repeat), Vec3::zero());
+        assert_eq!(image.sample(over, WrapMode::Mirror), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(image.sample(Vec2f::new(7.0, -3.0), WrapMode::Clamp), Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn checker_alternates() {
+        let checker = Texture::Checker {
+            even: Arc::new(Texture::solid(Vec3::ones())),
+            odd: Arc::new(Texture::solid(Vec3::zero())),
+            scale: 2.0,
+        };
+        let at = |x, y, z| checker.value(Vec2f::zero(), Vec3::new(x, y, z));
+        assert_eq!(at(0.5, 0.5, 0.5), Vec3::ones());
+        assert_eq!(at(2.5, 0.5, 0.5), Vec3::zero());
+        assert_eq!(at(2.5, -0.5, 0.5), Vec3::ones());
+        assert_eq!(at(-0.5, -0.5, -0.5), Vec3::zero());
+    }
+
+    #[test]
+    fn ppm_both_kinds() {
+        let (w, h, text) = read_ppm(b"P3\n# a comment\n2 1\n15\n15 0 0  0 15 15\n").unwrap();
+        assert_eq!((w, h), (2, 1));
+        assert_eq!(text, [1.0, 0.0, 0.0, 0.0, 1.0, 1.0]);
+
+        let mut binary = b"P6 2 1 255\n".to_vec();
+        binary.extend([255, 0, 0, 0, 255, 255]);
+        assert_eq!(read_ppm(&binary).unwrap().2, text);
+
+        binary.pop();
+        assert!(read_ppm(&binary).is_err());
+        assert!(read_ppm(b"P3 2 1 0 0 0 0 0 0 0").is_err());
+
+        // and 8-bit values get decoded from sRGB
+        let image = Image::from_rgb(1, 1, &[0.5, 0.5, 0.5], true);
+        assert!((image.pixels[0].x - 0.214).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,484 @@
+
+use crate::primitives::{
+    Vec2i,
+    Rect,
+};
+use crate::renderer::{
+    Tile,
+    Framebuffer,
+    RenderProperties,
+};
+use crate::scene::Scene;
+
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A unit of work handed to the worker threads.
+#[derive (Copy, Clone)]
+pub struct RenderCommand {
+    pub id: usize,
+    pub bounds: Rect,
+}
+
+// What the worker threads hand back.
+pub struct RenderResult {
+    pub id: usize,
+    pub tile: Tile,
+}
+
+pub struct ThreadProperties {
+    pub threads: usize,     // number of worker threads to spawn
+    pub tile_size: Vec2i,   // largest tile a worker will be asked to render
+}
+
+impl ThreadProperties {
+    // one worker per core, falling back to a single thread if the OS won't say
+    pub fn default_threads() -> usize {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    }
+}
+
+// Cut the image into tiles no larger than tile_size. Tiles along the right
+// and top edges are clipped to fit the image.
+pub fn make_tiles(img_size: Vec2i, tile_size: Vec2i) -> Vec<Rect> {
+    make_region_tiles(Rect { x: 0, y: 0, w: img_size.x, h: img_size.y }, tile_size)
+}
+
+// Same again for just one rectangle of the image
+pub fn make_region_tiles(region: Rect, tile_size: Vec2i) -> Vec<Rect> {
+    let mut tiles = Vec::new();
+    let (right, top) = (region.x + region.w, region.y + region.h);
+    for y in (region.y..top).step_by(tile_size.y as usize) {
+        for x in (region.x..right).step_by(tile_size.x as usize) {
+            tiles.push(Rect {
+                x,
+                y,
+                w: tile_size.x.min(right - x),
+                h: tile_size.y.min(top - y),
+            });
+        }
+    }
+    tiles
+}
+
+// Render the whole image on a pool of worker threads.
+pub fn render_parallel(
+    scene: &Scene,
+    properties: &RenderProperties,
+    thread_props: &ThreadProperties,
+    img_size: Vec2i,
+) -> Framebuffer {
+    let region = Rect { x: 0, y: 0, w: img_size.x, h: img_size.y };
+    render_region(scene, properties, thread_props, img_size, region)
+}
+
+// Render one rectangle of the image on a pool of worker threads. Pixels come
+// out as they would in a full render (up to rounding, when the filter reaches
+// across tiles); the framebuffer just doesn't hold the rest.
+pub fn render_region(
+    scene: &Scene,
+    properties: &RenderProperties,
+    thread_props: &ThreadProperties,
+    img_size: Vec2i,
+    region: Rect,
+) -> Framebuffer {
+    let mut framebuffer = Framebuffer::for_region(region);
+    let traced = traced_region(region, properties, img_size);
+    render_pass(thread_props, &mut framebuffer, traced, true, |bounds| {
+        Tile::render_tile(bounds, img_size, scene, properties)
+    });
+    framebuffer
+}
+
+// When a progressive render should stop. It always stops at the sample count
+// in the RenderProperties, and sooner if it runs out of time.
+pub struct ProgressiveProperties {
+    pub time_limit: Option<Duration>,
+}
+
+// Render one sample per pixel per pass, adding each pass into the same
+// framebuffer, so there's a complete (if noisy) picture early on. After every
+// pass, after_pass gets the framebuffer and the number of samples in it.
+// Because every sample has its own random stream, n passes give exactly the
+// image an n sample render would.
+//
+// framebuffer and samples are where to start from: a fresh framebuffer for
+// the region and 0, or a checkpoint. Returns the framebuffer and its sample
+// count.
+#[allow(clippy::too_many_arguments)] // the first five match render_region
+pub fn render_progressive(
+    scene: &Scene,
+    properties: &RenderProperties,
+    thread_props: &ThreadProperties,
+    img_size: Vec2i,
+    mut framebuffer: Framebuffer,
+    mut samples: u32,
+    progressive: &ProgressiveProperties,
+    mut after_pass: impl FnMut(&Framebuffer, u32),
+) -> (Framebuffer, u32) {
+    let start = Instant::now();
+    let region = Rect {
+        x: framebuffer.origin.x,
+        y: framebuffer.origin.y,
+        w: framebuffer.size.x,
+        h: framebuffer.size.y,
+    };
+    let traced = traced_region(region, properties, img_size);
+    while samples < properties.samples {
+        render_pass(thread_props, &mut framebuffer, traced, false, |bounds| {
+            Tile::render_samples(bounds, img_size, scene, properties, samples..samples + 1)
+        });
+        samples += 1;
+        after_pass(&framebuffer, samples);
+        if progressive.time_limit.is_some_and(|limit| start.elapsed() >= limit) {
+            break;
+        }
+    }
+    (framebuffer, samples)
+}
+
+// Pixels whose samples can land in region: the filter reaches a few pixels
+// past its edges, as far as the image goes
+pub fn traced_region(region: Rect, properties: &RenderProperties, img_size: Vec2i) -> Rect {
+    let margin = properties.filter.margin();
+    let (x0, y0) = ((region.x - margin).max(0), (region.y - margin).max(0));
+    let x1 = (region.x + region.w + margin).min(img_size.x);
+    let y1 = (region.y + region.h + margin).min(img_size.y);
+    Rect { x: x0, y: y0, w: x1 - x0, h: y1 - y0 }
+}
+
+// Render every tile of region with render_tile and add them into the
+// framebuffer.
+//
+// Every tile is queued up front on a shared job channel. Workers pull from
+// it until it runs dry, and send finished tiles back over a result channel.
+// The calling thread adds them to the framebuffer as they arrive.
+fn render_pass(
+    thread_props: &ThreadProperties,
+    framebuffer: &mut Framebuffer,
+    region: Rect,
+    report_tiles: bool,
+    render_tile: impl Fn(Rect) -> Tile + Sync,
+) {
+    let tiles = make_region_tiles(region, thread_props.tile_size);
+    let total_tiles = tiles.len();
+
+    let (job_tx, job_rx) = mpsc::channel::<RenderCommand>();
+    for (id, bounds) in tiles.into_iter().enumerate() {
+        job_tx.send(RenderCommand { id, bounds }).expect("Job queue closed early");
+    }
+    // Hanging up the sender means workers see an error once the queue is empty
+    drop(job_tx);
+    let job_rx = Mutex::new(job_rx);
+
+    let (result_tx, result_rx) = mpsc::channel::<RenderResult>();
+
+    thread::scope(|s| {
+        for _ in 0..thread_props.threads.max(1) {
+            let job_rx = &job_rx;
+            let render_tile = &render_tile;
+            let result_tx = result_tx.clone();
+            s.spawn(move || loop {
+                // the lock is released at the end of this statement, so the
+                // other workers aren't held up while this one renders
+                let command = job_rx.lock().unwrap().recv();
+                match command {
+                    Ok(RenderCommand { id, bounds }) => {
+                        let tile = render_tile(bounds);
+                        if result_tx.send(RenderResult { id, tile }).is_err() {
+                            break;
+                        }
+                    },
+                    Err(_) => break,
+                }
+            });
+        }
+        // only the workers hold senders now, so the loop below ends with them
+        drop(result_tx);
+
+        for (count, result) in result_rx.iter().enumerate() {
+            framebuffer.accumulate(&result.tile);
+            if report_tiles {
+                eprintln!("Finished tile #{} ({}/{})", result.id, count + 1, total_tiles);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Vec3;
+    use crate::filter::{Filter, FilterKind};
+    use crate::sampler::SamplerKind;
+    use crate::scene::{Background, Camera};
+
+    use rand::SeedableRng;
+    use rand::rngs::SmallRng;
+
+    #[test]
+    fn tiles_cover_image_once() {
+        let img_size = Vec2i { x: 100, y: 37 };
+        let tiles = make_tiles(img_size, Vec2i { x: 16, y: 16 });
+
+        let mut coverage = vec![0; (img_size.x * img_size.y) as usize];
+        for tile in &tiles {
+            for y in tile.y..tile.y + tile.h {
+                for x in tile.x..tile.x + tile.w {
+                    coverage[(y * img_size.x + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(coverage.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn tiles_clip_to_edges() {
+        let tiles = make_tiles(Vec2i { x: 20, y: 10 }, Vec2i { x: 16, y: 16 });
+        assert_eq!(tiles, vec![
+            Rect { x: 0, y: 0, w: 16, h: 10 },
+            Rect { x: 16, y: 0, w: 4, h: 10 },
+        ]);
+    }
+
+    #[test]
+    fn accumulate_places_tiles() {
+        let mut fb = Framebuffer::new(Vec2i { x: 3, y: 2 });
+        let tile = Tile {
+            bounds: Rect { x: 1, y: 1, w: 2, h: 1 },
+            pixels: vec![Vec3::ones(), Vec3::ones() * 2.0],
+            weights: vec![1.0, 1.0],
+            samples: 2,
+        };
+        fb.accumulate(&tile);
+        assert_eq!(fb.pixels, vec![
+            Vec3::zero(), Vec3::zero(), Vec3::zero(),
+            Vec3::zero(), Vec3::ones(), Vec3::ones() * 2.0,
+        ]);
+
+        // a tile with a filter margin hanging off the edges only adds what
+        // lands inside
+        let margin = Tile {
+            bounds: Rect { x: -1, y: 1, w: 5, h: 2 },
+            pixels: vec![Vec3::ones(); 10],
+            weights: vec![0.5; 10],
+            samples: 10,
+        };
+        fb.accumulate(&margin);
+        assert_eq!(fb.pixels[3..], [Vec3::ones(), Vec3::ones() * 2.0, Vec3::ones() * 3.0]);
+        assert_eq!(fb.weights, vec![0.0, 0.0, 0.0, 0.5, 1.5, 1.5]);
+    }
+
+    fn test_scene() -> Scene {
+        Scene::new(
+            Camera::new(
+                Vec3::new(13.0, 2.0, 3.0),
+                Vec3::zero(),
+                Vec3::new(0.0, 1.0, 0.0),
+                20.0,
+                1.5,
+                0.1,
+                10.0,
+            ),
+            Scene::random_world(&mut SmallRng::seed_from_u64(0), None, false),
+            Background::Sky,
+        )
+    }
+
+    #[test]
+    fn render_independent_of_scheduling() {
+        let img_size = Vec2i { x: 24, y: 16 };
+        let scene = test_scene();
+        for sampler in [SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol] {
+            let props = RenderProperties { samples: 2, bounces: 4, roulette_depth: 3, seed: 7, adaptive: None, sampler, filter: Filter::default() };
+
+            let serial = render_parallel(
+                &scene, &props,
+                &ThreadProperties { threads: 1, tile_size: img_size },
+                img_size,
+            );
+            let parallel = render_parallel(
+                &scene, &props,
+                &ThreadProperties { threads: 3, tile_size: Vec2i { x: 5, y: 3 } },
+                img_size,
+            );
+            assert_eq!(serial.pixels, parallel.pixels, "{:?}", sampler);
+        }
+    }
+
+    #[test]
+    fn region_matches_full_render() {
+        let img_size = Vec2i { x: 24, y: 16 };
+        let scene = test_scene();
+        let props = RenderProperties { samples: 2, bounces: 4, roulette_depth: 3, seed: 7, adaptive: None, sampler: SamplerKind::Independent, filter: Filter::default() };
+        let thread_props = ThreadProperties { threads: 2, tile_size: Vec2i { x: 4, y: 4 } };
+
+        let full = render_parallel(&scene, &props, &thread_props, img_size);
+        let region = Rect { x: 5, y: 3, w: 10, h: 7 };
+        let part = render_region(&scene, &props, &thread_props, img_size, region);
+        assert_eq!(part.size, Vec2i { x: 10, y: 7 });
+        for y in 0..region.h {
+            for x in 0..region.w {
+                let expected = full.pixels[((region.y + y) * img_size.x + region.x + x) as usize];
+                assert_eq!(part.pixels[(y * region.w + x) as usize], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn wide_filter_region_matches_full_render() {
+        // samples from just outside the region still reach its edge pixels
+        let img_size = Vec2i { x: 24, y: 16 };
+        let scene = test_scene();
+        let props = RenderProperties {
+            samples: 2,
+            bounces: 4,
+            roulette_depth: 3,
+            seed: 7,
+            adaptive: None,
+            sampler: SamplerKind::Sobol,
+            filter: Filter::new(FilterKind::Mitchell),
+        };
+        let thread_props = ThreadProperties { threads: 2, tile_size: Vec2i { x: 4, y: 4 } };
+
+        let full = render_parallel(&scene, &props, &thread_props, img_size);
+        let region = Rect { x: 5, y: 3, w: 10, h: 7 };
+        let part = render_region(&scene, &props, &thread_props, img_size, region);
+        for y in 0..region.h {
+            for x in 0..region.w {
+                let index = ((region.y + y) * img_size.x + region.x + x) as usize;
+                let (a, b) = (part.average((y * region.w + x) as usize), full.average(index));
+                assert!((a - b).length() <= 1e-4 * (1.0 + b.length()), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn progressive_matches_single_pass() {
+        let img_size = Vec2i { x: 12, y: 8 };
+        let scene = test_scene();
+        let props = RenderProperties { samples: 3, bounces: 4, roulette_depth: 3, seed: 7, adaptive: None, sampler: SamplerKind::Independent, filter: Filter::default() };
+        let thread_props = ThreadProperties { threads: 2, tile_size: Vec2i { x: 5, y: 5 } };
+        let region = Rect { x: 0, y: 0, w: img_size.x, h: img_size.y };
+
+        let mut passes = Vec::new();
+        let (progressive, samples) = render_progressive(
+            &scene, &props, &thread_props, img_size, Framebuffer::for_region(region), 0,
+            &ProgressiveProperties { time_limit: None },
+            |_, n| passes.push(n),
+        );
+        assert_eq!(samples, 3);
+        assert_eq!(passes, [1, 2, 3]);
+
+        // sums land in a different order, so allow for rounding
+        let whole = render_parallel(&scene, &props, &thread_props, img_size);
+        for (a, b) in progressive.pixels.iter().zip(&whole.pixels) {
+            assert!((*a - *b).length() <= 1e-4 * (1.0 + b.length()));
+        }
+        assert_eq!(progressive.weights, whole.weights);
+    }
+
+    #[test]
+    fn progressive_stops_on_time() {
+        let img_size = Vec2i { x: 4, y: 4 };
+        let props = RenderProperties { samples: 1000, bounces: 2, roulette_depth: 3, seed: 0, adaptive: None, sampler: SamplerKind::Independent, filter: Filter::default() };
+        let (_, samples) = render_progressive(
+            &test_scene(), &props,
+            &ThreadProperties { threads: 1, tile_size: img_size },
+            img_size, Framebuffer::new(img_size), 0,
+            &ProgressiveProperties { time_limit: Some(Duration::ZERO) },
+            |_, _| (),
+        );
+        assert_eq!(samples, 1);
+    }
+
+    #[test]
+    fn resumed_render_matches_uninterrupted() {
+        use crate::checkpoint::{RenderIdentity, read_checkpoint, write_checkpoint};
+
+        let img_size = Vec2i { x: 12, y: 8 };
+        let scene = test_scene();
+        let thread_props = ThreadProperties { threads: 2, tile_size: Vec2i { x: 5, y: 5 } };
+        let region = Rect { x: 0, y: 0, w: img_size.x, h: img_size.y };
+        let no_limit = ProgressiveProperties { time_limit: None };
+        let props = |samples| RenderProperties { samples, bounces: 4, roulette_depth: 3, seed: 7, adaptive: None, sampler: SamplerKind::Independent, filter: Filter::default() };
+
+        let (whole, _) = render_progressive(
+            &scene, &props(4), &thread_props, img_size,
+            Framebuffer::for_region(region), 0, &no_limit, |_, _| (),
+        );
+
+        // stop after two passes, save, load and carry on to four
+        let (half, samples) = render_progressive(
+            &scene, &props(2), &thread_props, img_size,
+            Framebuffer::for_region(region), 0, &no_limit, |_, _| (),
+        );
+        let identity = RenderIdentity { scene_hash: 1, seed: 7, sampler: SamplerKind::Independent, filter: Filter::default(), bounces: 4, roulette_depth: 3, img_size, region };
+        let mut bytes = Vec::new();
+        write_checkpoint(&mut bytes, &identity, &half, samples).unwrap();
+        let checkpoint = read_checkpoint(&mut &bytes[..]).unwrap();
+        let (resumed, samples) = render_progressive(
+            &scene, &props(4), &thread_props, img_size,
+            checkpoint.framebuffer, checkpoint.samples, &no_limit, |_, _| (),
+        );
+        assert_eq!(samples, 4);
+        assert_eq!(resumed.pixels, whole.pixels);
+        assert_eq!(resumed.weights, whole.weights);
+    }
+
+    #[test]
+    fn adaptive_spends_samples_on_noise() {
+        use crate::renderer::AdaptiveProperties;
+
+        // a noisy half (the ground and spheres) and a flat half (the sky)
+        let img_size = Vec2i { x: 16, y: 12 };
+        let scene = test_scene();
+        let props = RenderProperties {
+            samples: 64,
+            bounces: 4,
+            roulette_depth: 3,
+            seed: 7,
+            adaptive: Some(AdaptiveProperties { min_samples: 8, threshold: 0.05 }),
+            sampler: SamplerKind::Independent,
+            filter: Filter::default(),
+        };
+        let thread_props = ThreadProperties { threads: 2, tile_size: Vec2i { x: 5, y: 5 } };
+        let framebuffer = render_parallel(&scene, &props, &thread_props, img_size);
+
+        let (least, most) = framebuffer.weights.iter()
+            .fold((f32::MAX, 0.0f32), |(lo, hi), &w| (lo.min(w), hi.max(w)));
+        assert_eq!(least, 8.0); // the sky settles right away
+        assert_eq!(most, 64.0);
+        let again = render_parallel(&scene, &props, &ThreadProperties { threads: 1, tile_size: img_size }, img_size);
+        assert_eq!(framebuffer.pixels, again.pixels);
+    }
+
+    #[test]
+    fn roulette_keeps_the_brightness() {
+        let img_size = Vec2i { x: 16, y: 12 };
+        let scene = test_scene();
+        let thread_props = ThreadProperties { threads: 2, tile_size: Vec2i { x: 8, y: 8 } };
+        let brightness = |roulette_depth| {
+            let props = RenderProperties {
+                samples: 64,
+                bounces: 8,
+                roulette_depth, // 8 never gets to play
+                seed: 3,
+                adaptive: None,
+                sampler: SamplerKind::Independent,
+                filter: Filter::default(),
+            };
+            let framebuffer = render_parallel(&scene, &props, &thread_props, img_size);
+            let total: f32 = (0..framebuffer.pixels.len())
+                .map(|i| crate::tonemap::luminance(framebuffer.average(i)))
+                .sum();
+            total / framebuffer.pixels.len() as f32
+        };
+        let (full, roulette) = (brightness(8), brightness(0));
+        assert!((full - roulette).abs() < full * 0.02, "{} vs {}", full, roulette);
+    }
+}
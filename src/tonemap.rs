@@ -0,0 +1,160 @@
+
+// Getting from accumulated radiance to display values. The framebuffer holds
+// linear light with no upper limit; screens and 8-bit files want 0..1 in
+// sRGB. Exposure scales the light, a curve folds it into 0..1, and then the
+// sRGB transfer function encodes it.
+
+use crate::primitives::Vec3;
+
+use serde::Deserialize;
+
+#[derive (Copy, Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneCurve {
+    // cut off anything above 1, the way it's always been done here
+    #[default]
+    Clamp,
+    // L / (1 + L) on luminance, so hues survive the squeeze
+    Reinhard,
+    // John Hable's curve from Uncharted 2
+    Filmic,
+    // Krzysztof Narkowicz's fit of the ACES reference rendering transform
+    Aces,
+}
+
+impl ToneCurve {
+    pub fn from_name(name: &str) -> Option<ToneCurve> {
+        match name.to_ascii_lowercase().as_str() {
+            "clamp" => Some(ToneCurve::Clamp),
+            "reinhard" => Some(ToneCurve::Reinhard),
+            "filmic" => Some(ToneCurve::Filmic),
+            "aces" => Some(ToneCurve::Aces),
+            _ => None,
+        }
+    }
+}
+
+#[derive (Copy, Clone, PartialEq, Debug, Default)]
+pub struct ToneMap {
+    pub exposure: f32, // in stops, so +1 doubles the light
+    pub curve: ToneCurve,
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn aces(x: f32) -> f32 {
+    let x = x * 0.6; // the fit was made against inputs pre-exposed by this much
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+pub fn luminance(c: Vec3) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// Linear light to the sRGB encoding, both in 0..1
+pub fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// And back again, for colors read in from 8-bit images
+pub fn srgb_decode(v: f32) -> f32 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_byte(v: f32) -> u8 {
+    // NaN ends up as 0, same as a negative
+    (v.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+}
+
+impl ToneMap {
+    // Apply exposure and the curve, giving linear values in 0..1
+    pub fn map(&self, color: Vec3) -> Vec3 {
+        let c = color * 2f32.powf(self.exposure);
+        let mapped = match self.curve {
+            ToneCurve::Clamp => c,
+            ToneCurve::Reinhard => {
+                let l = luminance(c);
+                if l > 0.0 { c * (1.0 / (1.0 + l)) } else { c }
+            }
+            ToneCurve::Filmic => {
+                // the curve is built to sit at about 2x exposure, and is
+                // scaled so its white point, a linear input of 11.2 (5.6
+                // before the 2x), maps to 1
+                let white = hable(11.2);
+                Vec3::new(hable(2.0 * c.x), hable(2.0 * c.y), hable(2.0 * c.z)) * (1.0 / white)
+            }
+            ToneCurve::Aces => Vec3::new(aces(c.x), aces(c.y), aces(c.z)),
+        };
+        Vec3::new(
+            mapped.x.clamp(0.0, 1.0),
+            mapped.y.clamp(0.0, 1.0),
+            mapped.z.clamp(0.0, 1.0),
+        )
+    }
+
+    // Straight to 8-bit sRGB
+    pub fn to_rgb8(&self, color: Vec3) -> [u8; 3] {
+        let c = self.map(color);
+        [to_byte(srgb_encode(c.x)), to_byte(srgb_encode(c.y)), to_byte(srgb_encode(c.z))]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn srgb_transfer() {
+        assert_eq!(srgb_encode(0.0), 0.0);
+        assert!((srgb_encode(1.0) - 1.0).abs() < 1e-6);
+        // 18% grey lands a little below the middle of the range
+        assert!((srgb_encode(0.18) - 0.4614).abs() < 1e-3);
+        // the linear toe and the power segment meet up
+        let knee = 0.003_130_8;
+        assert!((srgb_encode(knee) - srgb_encode(knee + 1e-7)).abs() < 1e-5);
+        for v in [0.0, 0.002, 0.18, 0.5, 1.0] {
+            assert!((srgb_decode(srgb_encode(v)) - v).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn curves_stay_in_range() {
+        for curve in [ToneCurve::Clamp, ToneCurve::Reinhard, ToneCurve::Filmic, ToneCurve::Aces] {
+            let tone_map = ToneMap { exposure: 0.0, curve };
+            let mut last = -1.0;
+            for i in 0..200 {
+                let v = (i as f32 * 0.1).powi(2);
+                let mapped = tone_map.map(Vec3::new(v, v, v)).x;
+                assert!((0.0..=1.0).contains(&mapped), "{:?} gave {} for {}", curve, mapped, v);
+                assert!(mapped >= last, "{:?} isn't monotonic at {}", curve, v);
+                last = mapped;
+            }
+            assert_eq!(tone_map.to_rgb8(Vec3::zero()), [0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn exposure_in_stops() {
+        let tone_map = ToneMap { exposure: 2.0, curve: ToneCurve::Clamp };
+        assert_eq!(tone_map.map(Vec3::new(0.1, 0.2, 0.5)), Vec3::new(0.4, 0.8, 1.0));
+    }
+
+    #[test]
+    fn reinhard_keeps_hue() {
+        let tone_map = ToneMap { exposure: 0.0, curve: ToneCurve::Reinhard };
+        let mapped = tone_map.map(Vec3::new(0.4, 0.2, 0.1));
+        assert!((mapped.x / mapped.y - 2.0).abs() < 1e-5);
+        assert!((mapped.y / mapped.z - 2.0).abs() < 1e-5);
+    }
+}